@@ -1,4 +1,3 @@
-#![feature(async_closure)] // not actually necessary, just added to test that feature defs work.
 #[macro_use]
 extern crate lazy_static;
 #[macro_use(c)]
@@ -1,7 +1,7 @@
-#![feature(async_closure)] // not actually necessary, just added to test that feature defs work.
 extern crate proc_macro;
 use futures::future::try_join_all;
 use rand;
+use serde::{Deserialize, Serialize};
 use turbolift::local_queue::LocalQueue;
 use turbolift::on;
 #[macro_use]
@@ -20,6 +20,23 @@ fn identity(b: bool) -> bool {
     b
 }
 
+#[on(LOCAL)]
+fn negate(b: bool) -> bool {
+    !b
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Toggle {
+    state: bool,
+}
+
+impl Toggle {
+    #[on(LOCAL)]
+    fn flipped(&self) -> Toggle {
+        Toggle { state: !self.state }
+    }
+}
+
 fn main() {
     // use tracing.rs to print info about the program to stdout
     tracing_subscriber::fmt()
@@ -35,13 +52,21 @@ fn main() {
         }
         v
     };
+    let negated_futures = {
+        let mut v = Vec::new();
+        for b in &input {
+            v.push(negate(*b));
+        }
+        v
+    };
     let mut rt = tokio::runtime::Runtime::new().unwrap();
     let output = rt.block_on(try_join_all(futures)).unwrap();
+    let negated_output = rt.block_on(try_join_all(negated_futures)).unwrap();
     println!(
-        "\n\nAll responses received.\ninput: {:?}\noutput: {:?}",
-        input, output
+        "\n\nAll responses received.\ninput: {:?}\noutput: {:?}\nnegated output: {:?}",
+        input, output, negated_output
     );
-    if output != input {
+    if output != input || negated_output != input.iter().map(|b| !b).collect::<Vec<bool>>() {
         std::process::exit(1)
     }
 }
@@ -65,4 +90,31 @@ mod tests {
         let output = rt.block_on(try_join_all(futures)).unwrap();
         assert_eq!(input, output);
     }
+
+    #[test]
+    fn multiple_functions() {
+        let input = vec![rand::random(), rand::random(), rand::random()];
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let output = rt
+            .block_on(try_join_all(input.iter().map(|b| identity(*b))))
+            .unwrap();
+        let negated_output = rt
+            .block_on(try_join_all(input.iter().map(|b| negate(*b))))
+            .unwrap();
+        assert_eq!(input, output);
+        assert_eq!(
+            input.iter().map(|b| !b).collect::<Vec<bool>>(),
+            negated_output
+        );
+    }
+
+    #[test]
+    fn methods() {
+        let toggle = Toggle {
+            state: rand::random(),
+        };
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let flipped = rt.block_on(toggle.flipped()).unwrap();
+        assert_eq!(flipped.state, !toggle.state);
+    }
 }
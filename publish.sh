@@ -2,8 +2,8 @@
 
 set -e
 
-cd ./turbolift_internals && RUSTFLAGS='--cfg procmacro2_semver_exempt' cargo publish
+cd ./turbolift_internals && cargo publish
 sleep 70
-cd ../turbolift_macros && RUSTFLAGS='--cfg procmacro2_semver_exempt' cargo publish
+cd ../turbolift_macros && cargo publish
 sleep 70
-cd .. && RUSTFLAGS='--cfg procmacro2_semver_exempt' cargo publish
+cd .. && cargo publish
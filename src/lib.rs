@@ -2,6 +2,8 @@
 pub use chrono;
 
 pub use actix_web;
+pub use futures;
+pub use serde;
 pub use serde_json;
 pub use tokio_compat_02;
 pub use tracing;
@@ -0,0 +1,428 @@
+use std::time::Duration;
+
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+
+use crate::codec::Codec;
+use crate::resources::{is_valid_cpu, parse_memory, ResourceHints};
+
+/// The arguments of the `on` attribute, e.g. `#[on(K8S, codec = "bincode")]`.
+/// The distribution platform always comes first, followed by optional
+/// `key = value` settings.
+pub struct OnArgs {
+    pub distribution_platform: syn::Expr,
+    /// overrides the platform's default codec for this function.
+    pub codec: Option<Codec>,
+    /// overrides the platform's default timeout for each call, e.g.
+    /// `timeout = "30s"`.
+    pub timeout: Option<Duration>,
+    /// marks the function as safe to call more than once with the same
+    /// arguments, which lets the platform retry it after transient failures.
+    pub idempotent: bool,
+    /// overrides the number of retries in the platform's retry policy, e.g.
+    /// `retries = 5`. Requires `idempotent`.
+    pub retries: Option<u32>,
+    /// the concrete types to distribute a generic function for, e.g.
+    /// `instantiate(f64, u32)`. Functions with several type parameters list a
+    /// tuple per instantiation, e.g. `instantiate((f64, u8), (u32, u8))`.
+    pub instantiate: Vec<syn::Type>,
+    /// groups the function with the other functions of the same service, e.g.
+    /// `service = "math"`, so that they share a worker.
+    pub service: Option<String>,
+}
+
+impl Parse for OnArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                input.span(),
+                "expected the distribution platform to run the function on, e.g. `#[on(K8S)]`",
+            ));
+        }
+        let mut args = OnArgs {
+            distribution_platform: input.parse()?,
+            codec: None,
+            timeout: None,
+            idempotent: false,
+            retries: None,
+            instantiate: Vec::new(),
+            service: None,
+        };
+        let mut retries_key = None;
+        while !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: syn::Ident = input.parse()?;
+            match key.to_string().as_str() {
+                "codec" => {
+                    let value = parse_value::<syn::LitStr>(input)?;
+                    args.codec = Some(Codec::from_name(&value.value()).ok_or_else(|| {
+                        syn::Error::new(
+                            value.span(),
+                            "unknown codec, expected one of: \"json\", \"bincode\", \"msgpack\"",
+                        )
+                    })?);
+                }
+                "timeout" => args.timeout = Some(parse_duration(input)?),
+                "idempotent" => args.idempotent = true,
+                "instantiate" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    let types =
+                        syn::punctuated::Punctuated::<syn::Type, syn::Token![,]>::parse_terminated(
+                            &content,
+                        )?;
+                    if types.is_empty() {
+                        return Err(syn::Error::new(
+                            key.span(),
+                            "`instantiate` needs at least one type",
+                        ));
+                    }
+                    args.instantiate.extend(types);
+                }
+                "retries" => {
+                    args.retries = Some(parse_value::<syn::LitInt>(input)?.base10_parse()?);
+                    retries_key = Some(key);
+                }
+                "service" => {
+                    let value = parse_value::<syn::LitStr>(input)?;
+                    if !is_valid_service_name(&value.value()) {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "service names must start with a lowercase letter and only contain lowercase letters, digits, and underscores",
+                        ));
+                    }
+                    args.service = Some(value.value());
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        format!("unknown argument `{}` for `on`", key),
+                    ))
+                }
+            }
+        }
+        match retries_key {
+            Some(key) if !args.idempotent => Err(syn::Error::new(
+                key.span(),
+                "`retries` requires `idempotent`, since a failed call may still have run",
+            )),
+            _ => Ok(args),
+        }
+    }
+}
+
+/// service names are used in routes, package names, and kubernetes resource
+/// names, so they're restricted to the characters that are valid in all of them.
+fn is_valid_service_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// The arguments of the `with` attribute, e.g.
+/// `#[with(cpu = "500m", memory = "256Mi", env(RUST_LOG = "debug"))]`.
+#[derive(Default)]
+pub struct WithArgs {
+    pub resources: ResourceHints,
+    /// same as `timeout` in the `on` attribute.
+    pub timeout: Option<Duration>,
+}
+
+impl Parse for WithArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = WithArgs::default();
+        while !input.is_empty() {
+            let key: syn::Ident = input.parse()?;
+            match key.to_string().as_str() {
+                "cpu" => {
+                    let value = parse_value::<syn::Lit>(input)?;
+                    let cpu = match &value {
+                        syn::Lit::Str(s) => s.value(),
+                        syn::Lit::Int(i) => i.base10_digits().to_string(),
+                        syn::Lit::Float(f) => f.base10_digits().to_string(),
+                        _ => String::new(),
+                    };
+                    if !is_valid_cpu(&cpu) {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "invalid cpu, expected cores (e.g. \"2\" or \"0.5\") or millicores (e.g. \"500m\")",
+                        ));
+                    }
+                    args.resources.cpu = Some(cpu);
+                }
+                "memory" => {
+                    let value = parse_value::<syn::LitStr>(input)?;
+                    if parse_memory(&value.value()).is_none() {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "invalid memory, expected bytes with an optional suffix (e.g. \"256Mi\" or \"1G\")",
+                        ));
+                    }
+                    args.resources.memory = Some(value.value());
+                }
+                "replicas" => {
+                    let value = parse_value::<syn::LitInt>(input)?;
+                    let replicas: u32 = value.base10_parse()?;
+                    if replicas == 0 {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "a function needs at least one replica",
+                        ));
+                    }
+                    args.resources.replicas = Some(replicas);
+                }
+                "concurrency" => {
+                    let value = parse_value::<syn::LitInt>(input)?;
+                    let concurrency: u32 = value.base10_parse()?;
+                    if concurrency == 0 {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "a worker needs to run at least one call at a time",
+                        ));
+                    }
+                    args.resources.concurrency = Some(concurrency);
+                }
+                "env" => {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    while !content.is_empty() {
+                        let name: syn::Ident = content.parse()?;
+                        let value = parse_value::<syn::LitStr>(&content)?;
+                        args.resources.env.push((name.to_string(), value.value()));
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
+                }
+                "timeout" => args.timeout = Some(parse_duration(input)?),
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        format!("unknown argument `{}` for `with`", key),
+                    ))
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// whether an attribute is `#[with(..)]`, however the macro was imported.
+pub fn is_with_attribute(attr: &syn::Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "with")
+}
+
+/// whether an attribute is `#[on(..)]`, however the macro was imported.
+pub fn is_on_attribute(attr: &syn::Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "on")
+}
+
+/// what `on` leaves in place of the `with` attributes it consumes, so that the
+/// `with` import is still used. `with` ignores attributes with this argument.
+pub const APPLIED_WITH_MARKER: &str = "__turbolift_applied";
+
+/// whether a `with` attribute is the marker left by `on`.
+pub fn is_applied_with_marker(attr: &syn::Attribute) -> bool {
+    attr.tokens.to_string() == format!("({})", APPLIED_WITH_MARKER)
+}
+
+/// removes the `with` attributes from a function and merges their arguments
+/// into the `on` attribute's, so that `on` can pass them to the platform. A
+/// single marker attribute is left behind, see [`APPLIED_WITH_MARKER`].
+pub fn take_with_attributes(
+    on_args: &mut OnArgs,
+    attrs: &mut Vec<syn::Attribute>,
+) -> syn::Result<ResourceHints> {
+    let mut resources = ResourceHints::default();
+    let mut result = Ok(());
+    let mut with_path = None;
+    attrs.retain(|attr| {
+        if !is_with_attribute(attr) || is_applied_with_marker(attr) {
+            return true;
+        }
+        with_path.get_or_insert_with(|| attr.path.clone());
+        match attr.parse_args::<WithArgs>() {
+            Ok(with_args) => {
+                let hints = with_args.resources;
+                resources.cpu = hints.cpu.or(resources.cpu.take());
+                resources.memory = hints.memory.or(resources.memory.take());
+                resources.replicas = hints.replicas.or(resources.replicas);
+                resources.concurrency = hints.concurrency.or(resources.concurrency);
+                resources.env.extend(hints.env);
+                if with_args.timeout.is_some() {
+                    if on_args.timeout.is_some() {
+                        result = Err(syn::Error::new_spanned(
+                            attr,
+                            "timeout is set more than once",
+                        ));
+                    }
+                    on_args.timeout = with_args.timeout;
+                }
+            }
+            Err(e) => result = Err(e),
+        }
+        false
+    });
+    if let Some(path) = with_path {
+        let marker = quote::format_ident!("{}", APPLIED_WITH_MARKER);
+        attrs.push(syn::parse_quote! { #[#path(#marker)] });
+    }
+    result.map(|()| resources)
+}
+
+/// attributes that move from a distributed function to the async function that
+/// callers use, since the original is only called by turbolift.
+const CLIENT_ATTRIBUTES: [&str; 2] = ["doc", "deprecated"];
+
+/// attributes that apply to both the distributed function and the async
+/// function that callers use.
+const SHARED_ATTRIBUTES: [&str; 6] = ["allow", "warn", "deny", "forbid", "cfg", "cfg_attr"];
+
+/// removes the docs and `deprecated` attribute from a distributed function and
+/// returns them for the async function that callers use, with copies of its
+/// lint and `cfg` attributes. The docs get a note about the result type.
+pub fn take_client_attributes(attrs: &mut Vec<syn::Attribute>) -> Vec<syn::Attribute> {
+    let is_one_of =
+        |attr: &syn::Attribute, names: &[&str]| names.iter().any(|name| attr.path.is_ident(name));
+    let mut client_attrs: Vec<syn::Attribute> = attrs
+        .iter()
+        .filter(|attr| is_one_of(attr, &CLIENT_ATTRIBUTES) || is_one_of(attr, &SHARED_ATTRIBUTES))
+        .cloned()
+        .collect();
+    attrs.retain(|attr| !is_one_of(attr, &CLIENT_ATTRIBUTES));
+
+    let note = " Distributed with turbolift: the result is wrapped in a \
+        `turbolift::DistributionResult`, which is an error if the call could not be completed.";
+    let documented = client_attrs.iter().any(|attr| attr.path.is_ident("doc"));
+    if documented {
+        client_attrs.push(syn::parse_quote!(#[doc = ""]));
+    }
+    client_attrs.push(syn::parse_quote!(#[doc = #note]));
+    client_attrs
+}
+
+/// parses the `= value` part of a `key = value` argument.
+fn parse_value<T: Parse>(input: ParseStream) -> syn::Result<T> {
+    input.parse::<syn::Token![=]>()?;
+    input.parse()
+}
+
+/// parses `= "30s"`, `= "1m 30s"`, `= "500ms"` etc.
+fn parse_duration(input: ParseStream) -> syn::Result<Duration> {
+    let value = parse_value::<syn::LitStr>(input)?;
+    humantime::parse_duration(&value.value())
+        .map_err(|e| syn::Error::new(value.span(), format!("invalid duration: {}", e)))
+}
+
+/// `Some(std::time::Duration::new(..))` or `None`, for use in generated code.
+pub fn duration_tokens(duration: Option<Duration>) -> TokenStream2 {
+    match duration {
+        Some(duration) => {
+            let secs = duration.as_secs();
+            let nanos = duration.subsec_nanos();
+            quote! { Some(std::time::Duration::new(#secs, #nanos)) }
+        }
+        None => quote! { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_platform_and_settings() {
+        let args: OnArgs = syn::parse_str(r#"K8S, codec = "bincode", timeout = "1m 30s""#).unwrap();
+        assert_eq!(args.codec, Some(Codec::Bincode));
+        assert_eq!(args.timeout, Some(Duration::from_secs(90)));
+        assert!(!args.idempotent);
+
+        let args: OnArgs = syn::parse_str("K8S, idempotent, retries = 5").unwrap();
+        assert!(args.idempotent);
+        assert_eq!(args.retries, Some(5));
+
+        let args: OnArgs = syn::parse_str("K8S, instantiate(f64, (u32, Vec<u8>))").unwrap();
+        assert_eq!(args.instantiate.len(), 2);
+
+        let args: OnArgs = syn::parse_str(r#"K8S, service = "math_2""#).unwrap();
+        assert_eq!(args.service.as_deref(), Some("math_2"));
+
+        let args: OnArgs = syn::parse_str("LOCAL").unwrap();
+        assert_eq!(args.codec, None);
+        assert_eq!(args.timeout, None);
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(syn::parse_str::<OnArgs>(r#"K8S, timeout = "soon""#).is_err());
+        assert!(syn::parse_str::<OnArgs>(r#"K8S, colour = "blue""#).is_err());
+        assert!(syn::parse_str::<OnArgs>("K8S, retries = 5").is_err());
+        assert!(syn::parse_str::<OnArgs>(r#"K8S, service = "Math""#).is_err());
+    }
+
+    #[test]
+    fn moves_docs_to_the_client() {
+        let mut function: syn::ItemFn = syn::parse_str(
+            r#"
+            /// adds one.
+            #[deprecated]
+            #[allow(clippy::identity_op)]
+            #[inline]
+            fn f() {}
+            "#,
+        )
+        .unwrap();
+        let client_attrs = take_client_attributes(&mut function.attrs);
+        let names = |attrs: &[syn::Attribute]| {
+            attrs
+                .iter()
+                .map(|attr| attr.path.get_ident().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&function.attrs), ["allow", "inline"]);
+        assert_eq!(
+            names(&client_attrs),
+            ["doc", "deprecated", "allow", "doc", "doc"]
+        );
+    }
+
+    #[test]
+    fn merges_with_attributes() {
+        let mut function: syn::ItemFn = syn::parse_str(
+            r#"
+            #[with(cpu = 2, memory = "256Mi", env(RUST_LOG = "debug", MODE = "fast"))]
+            #[with(replicas = 3, concurrency = 4, timeout = "10s")]
+            #[inline]
+            fn f() {}
+            "#,
+        )
+        .unwrap();
+        let mut on_args: OnArgs = syn::parse_str("K8S").unwrap();
+        let resources = take_with_attributes(&mut on_args, &mut function.attrs).unwrap();
+        assert_eq!(function.attrs.len(), 2);
+        assert!(is_applied_with_marker(&function.attrs[1]));
+        assert_eq!(resources.cpu.as_deref(), Some("2"));
+        assert_eq!(resources.memory_bytes(), Some(256 << 20));
+        assert_eq!(resources.replicas, Some(3));
+        assert_eq!(resources.concurrency, Some(4));
+        assert_eq!(resources.env.len(), 2);
+        assert_eq!(on_args.timeout, Some(Duration::from_secs(10)));
+
+        assert!(syn::parse_str::<WithArgs>(r#"memory = "a lot""#).is_err());
+        assert!(syn::parse_str::<WithArgs>("replicas = 0").is_err());
+    }
+}
@@ -1,11 +1,13 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
 
-use crate::utils::{symlink_dir, IS_RELEASE, RELEASE_FLAG};
+use crate::extract_function::IGNORED_DIRECTORIES;
+use crate::utils::{symlink_dir, StableHasher, IS_RELEASE, RELEASE_FLAG};
+use crate::CACHE_PATH;
 
 #[tracing::instrument]
 pub fn edit_cargo_file(
@@ -17,19 +19,24 @@ pub fn edit_cargo_file(
 
     let local_deps_dir_name = ".local_deps";
     let mut parsed_toml: cargo_toml2::CargoToml = cargo_toml2::from_path(cargo_path)
-        .unwrap_or_else(|_| panic!("toml at {:?} could not be read", cargo_path));
-    let relative_local_deps_cache = cargo_path.parent().unwrap().join(local_deps_dir_name);
+        .map_err(|e| anyhow::anyhow!("toml at {:?} could not be read: {}", cargo_path, e))?;
+    let relative_local_deps_cache = cargo_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{:?} has no parent directory", cargo_path))?
+        .join(local_deps_dir_name);
     fs::create_dir_all(&relative_local_deps_cache)?;
     let local_deps_cache = relative_local_deps_cache.canonicalize()?;
 
     // change name
     parsed_toml.package.name = function_name.to_string() + "_turbolift";
 
+    // make the project its own workspace, since the cache may be inside the
+    // original project's workspace without being one of its members
+    parsed_toml.package.workspace = None;
+    parsed_toml.workspace = Some(Default::default());
+
     // symlink any local directories so they work with the new project location
-    let mut deps = match parsed_toml.dependencies {
-        Some(deps) => deps,
-        None => Default::default(),
-    };
+    let mut deps = parsed_toml.dependencies.unwrap_or_default();
     let details = deps
         .iter_mut()
         // only full dependency descriptions (not simple version number)
@@ -124,7 +131,7 @@ fn exclusive_recursive_copy(
         if exclude_paths.contains(&entry_path)
             || entry_path
                 .file_name()
-                .map_or(false, |f| exclude_file_names.contains(f))
+                .is_some_and(|f| exclude_file_names.contains(f))
         {
             // skip the excluded path (and, if it has any, all of its children)
         } else {
@@ -142,6 +149,182 @@ fn exclusive_recursive_copy(
     Ok(())
 }
 
+/// identifies the contents of a derived project, so that an unchanged project
+/// can reuse its archive, binary, and image. Covers the files of the original
+/// project with `replaced_files` in place of the originals, its Cargo.lock
+/// (which is at the workspace root for workspace members), the local
+/// dependencies in its manifest, the project name, the toolchain, and the
+/// build profile.
+#[tracing::instrument(skip(replaced_files))]
+pub fn source_hash(
+    original_project_source_dir: &Path,
+    project_name: &str,
+    replaced_files: &[(PathBuf, String)],
+) -> anyhow::Result<String> {
+    let mut hasher = StableHasher::new();
+    hasher.write(project_name.as_bytes());
+    hasher.write(RELEASE_FLAG.as_bytes());
+    hasher.write(toolchain_version()?.as_bytes());
+
+    let project_canonical = original_project_source_dir.canonicalize()?;
+    let replaced_files = replaced_files
+        .iter()
+        .map(|(path, contents)| (project_canonical.join(path), contents.as_bytes()))
+        .collect();
+    hash_directory(
+        &project_canonical,
+        &project_canonical,
+        &replaced_files,
+        &mut hasher,
+    )?;
+
+    if let Some(lockfile) = workspace_lockfile(&project_canonical)? {
+        hasher.write(&fs::read(lockfile)?);
+    }
+
+    // local dependencies are copied or symlinked into the project by edit_cargo_file
+    let manifest: toml::Value =
+        fs::read_to_string(project_canonical.join("Cargo.toml"))?.parse()?;
+    let local_deps = manifest
+        .get("dependencies")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, dep)| Some((name, dep.get("path")?.as_str()?)));
+    for (name, path) in local_deps {
+        let dep_canonical = project_canonical.join(path).canonicalize()?;
+        if !dep_canonical.starts_with(&project_canonical) {
+            hasher.write(name.as_bytes());
+            hash_directory(
+                &dep_canonical,
+                &project_canonical,
+                &HashMap::new(),
+                &mut hasher,
+            )?;
+        }
+    }
+    let mut hash = hasher.finish();
+    hash.truncate(SOURCE_HASH_LEN);
+    Ok(hash)
+}
+
+/// the number of hex digits of a source hash kept in the names of cached
+/// artifacts.
+const SOURCE_HASH_LEN: usize = 16;
+
+/// the suffixes of the artifacts cached for a derived project, which are named
+/// `{project_name}_{source_hash}_{suffix}`: its archive, its local server
+/// binary, and the tag of its kubernetes image.
+const CACHED_ARTIFACT_SUFFIXES: [&str; 3] = ["source.tar", "server", "image"];
+
+/// removes the artifacts of older versions of a derived project from the
+/// cache directory, since they won't be used again.
+#[tracing::instrument]
+pub fn remove_outdated_artifacts(
+    cache_dir: &Path,
+    project_name: &str,
+    source_hash: &str,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        let is_outdated = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .and_then(|name| name.strip_prefix(project_name)?.strip_prefix('_'))
+            .and_then(|name| {
+                CACHED_ARTIFACT_SUFFIXES
+                    .iter()
+                    .find_map(|suffix| name.strip_suffix(suffix)?.strip_suffix('_'))
+            })
+            .is_some_and(|hash| {
+                hash.len() == source_hash.len()
+                    && hash != source_hash
+                    && hash.chars().all(|c| c.is_ascii_hexdigit())
+            });
+        if is_outdated {
+            tracing::info!(?path, "removing outdated artifact");
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// hashes the paths and contents of the files in a directory in a stable
+/// order, skipping the excluded path and ignored directories.
+fn hash_directory(
+    dir: &Path,
+    exclude_path: &Path,
+    replaced_files: &HashMap<PathBuf, &[u8]>,
+    hasher: &mut StableHasher,
+) -> anyhow::Result<()> {
+    let mut to_check = VecDeque::from(vec![dir.to_path_buf()]);
+    while let Some(current) = to_check.pop_front() {
+        let mut entries = fs::read_dir(&current)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for path in entries {
+            let is_ignored = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| IGNORED_DIRECTORIES.contains(&name));
+            if path.as_path() == exclude_path || (is_ignored && path.is_dir()) {
+                continue;
+            }
+            hasher.write(path.strip_prefix(dir)?.to_string_lossy().as_bytes());
+            if path.is_dir() {
+                to_check.push_back(path);
+            } else if let Some(contents) = replaced_files.get(&path) {
+                hasher.write(contents);
+            } else {
+                hasher.write(&fs::read(&path)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the lockfile of the workspace that the project is a member of, if the
+/// workspace root is above the project (otherwise the lockfile is one of the
+/// project's files).
+fn workspace_lockfile(project_canonical: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let output = Command::new("cargo")
+        .current_dir(project_canonical)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "could not locate the workspace of {:?}: {}",
+            project_canonical,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let workspace_manifest = PathBuf::from(String::from_utf8(output.stdout)?.trim());
+    let lockfile = workspace_manifest
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{:?} has no parent directory", workspace_manifest))?
+        .join("Cargo.lock");
+    if !lockfile.exists() {
+        return Ok(None);
+    }
+    let lockfile = lockfile.canonicalize()?;
+    if lockfile.starts_with(project_canonical) {
+        Ok(None)
+    } else {
+        Ok(Some(lockfile))
+    }
+}
+
+fn toolchain_version() -> anyhow::Result<String> {
+    let output = Command::new("rustc")
+        .args(["--version", "--verbose"])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("could not determine the rustc version"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
 #[tracing::instrument]
 pub fn lint(proj_path: &Path) -> anyhow::Result<()> {
     let install_status = Command::new("rustup")
@@ -163,10 +346,24 @@ pub fn lint(proj_path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// the target directory that all derived projects build in, so that their
+/// dependencies are only compiled once. Cargo writes every file in it itself
+/// (compiled files are never copied between target directories, which caused
+/// permission errors) and locks it, so projects can build at the same time.
+/// Only local builds use it; kubernetes images are built inside Docker, which
+/// can't see the directory, so each image compiles its dependencies again.
+pub fn shared_target_dir() -> anyhow::Result<PathBuf> {
+    let target_dir = CACHE_PATH.join("target");
+    fs::create_dir_all(&target_dir)?;
+    Ok(target_dir.canonicalize()?)
+}
+
 #[tracing::instrument]
 pub fn make_executable(proj_path: &Path, dest: Option<&Path>) -> anyhow::Result<()> {
+    let target_dir = shared_target_dir()?;
     let status = Command::new("cargo")
         .current_dir(proj_path)
+        .env("CARGO_TARGET_DIR", &target_dir)
         .args(format!("build{}", RELEASE_FLAG).as_str().trim().split(' '))
         .status()?;
 
@@ -181,12 +378,8 @@ pub fn make_executable(proj_path: &Path, dest: Option<&Path>) -> anyhow::Result<
             let cargo_path = proj_path.join("Cargo.toml");
             let parsed_toml: cargo_toml2::CargoToml = cargo_toml2::from_path(cargo_path)?;
             let project_name = parsed_toml.package.name;
-            let local_path = if IS_RELEASE {
-                "target/release/".to_string() + &project_name
-            } else {
-                "target/debug/".to_string() + &project_name
-            };
-            proj_path.canonicalize().unwrap().join(&local_path)
+            let profile_dir = if IS_RELEASE { "release" } else { "debug" };
+            target_dir.join(profile_dir).join(project_name)
         };
         fs::rename(&executable_path, destination)?;
     }
@@ -197,6 +390,7 @@ pub fn make_executable(proj_path: &Path, dest: Option<&Path>) -> anyhow::Result<
 pub fn check(proj_path: &Path) -> anyhow::Result<()> {
     let status = Command::new("cargo")
         .current_dir(proj_path)
+        .env("CARGO_TARGET_DIR", shared_target_dir()?)
         .args("check".split(' '))
         .status()?;
 
@@ -208,3 +402,96 @@ pub fn check(proj_path: &Path) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_project_sources() {
+        let project = tempfile::tempdir().unwrap();
+        fs::write(
+            project.path().join("Cargo.toml"),
+            "[package]\nname = \"hashed\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::create_dir_all(project.path().join("src")).unwrap();
+        fs::write(project.path().join("src").join("main.rs"), "fn main() {}").unwrap();
+        let hash = |replaced_files: &[(PathBuf, String)]| {
+            source_hash(project.path(), "hashed", replaced_files).unwrap()
+        };
+        let original = hash(&[]);
+        assert_eq!(original.len(), 16);
+        assert_eq!(hash(&[]), original);
+
+        // build artifacts don't change the source
+        fs::create_dir_all(project.path().join("target")).unwrap();
+        fs::write(project.path().join("target").join("artifact"), "0").unwrap();
+        assert_eq!(hash(&[]), original);
+
+        let worker_main = (PathBuf::from("src/main.rs"), "fn main() { 1; }".to_string());
+        let worker_main = std::slice::from_ref(&worker_main);
+        assert_ne!(hash(worker_main), original);
+        assert_eq!(hash(worker_main), hash(worker_main));
+
+        fs::write(project.path().join("Cargo.lock"), "").unwrap();
+        assert_ne!(hash(&[]), original);
+    }
+
+    #[test]
+    fn hashes_workspace_lockfile() {
+        let workspace = tempfile::tempdir().unwrap();
+        fs::write(
+            workspace.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .unwrap();
+        let member = workspace.path().join("member");
+        fs::create_dir_all(member.join("src")).unwrap();
+        fs::write(
+            member.join("Cargo.toml"),
+            "[package]\nname = \"member\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(member.join("src").join("main.rs"), "fn main() {}").unwrap();
+        let hash = || source_hash(&member, "member", &[]).unwrap();
+        let original = hash();
+
+        fs::write(workspace.path().join("Cargo.lock"), "version = 3\n").unwrap();
+        let locked = hash();
+        assert_ne!(locked, original);
+        fs::write(workspace.path().join("Cargo.lock"), "version = 4\n").unwrap();
+        assert_ne!(hash(), locked);
+    }
+
+    #[test]
+    fn removes_outdated_artifacts() {
+        let cache = tempfile::tempdir().unwrap();
+        let artifact = |name: &str| cache.path().join(name);
+        let current = [
+            "pruned_0123456789abcdef_source.tar",
+            "pruned_0123456789abcdef_server",
+            "pruned_0123456789abcdef_image",
+        ];
+        let outdated = [
+            "pruned_fedcba9876543210_source.tar",
+            "pruned_fedcba9876543210_server",
+            "pruned_fedcba9876543210_image",
+        ];
+        let unrelated = [
+            "pruned_other_fedcba9876543210_server",
+            "pruned_not_a_hash_server",
+        ];
+        for name in current.iter().chain(&outdated).chain(&unrelated) {
+            fs::write(artifact(name), "").unwrap();
+        }
+
+        remove_outdated_artifacts(cache.path(), "pruned", "0123456789abcdef").unwrap();
+        for name in current.iter().chain(&unrelated) {
+            assert!(artifact(name).exists(), "{} was removed", name);
+        }
+        for name in &outdated {
+            assert!(!artifact(name).exists(), "{} was kept", name);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::distributed_platform::{DistributionError, DistributionResult};
+
+/// The wire format used for a distributed function's arguments and results.
+/// The dispatcher announces the codec in the request's content-type header,
+/// and the generated server answers using the same codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    Bincode,
+    MessagePack,
+}
+
+impl Codec {
+    /// parses the name used for the codec in the `on` attribute, e.g.
+    /// `#[on(K8S, codec = "bincode")]`.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(Codec::Json),
+            "bincode" => Some(Codec::Bincode),
+            "msgpack" | "messagepack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::Bincode => "application/x-bincode",
+            Codec::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// returns the codec for a content-type header value, ignoring any
+    /// parameters like `; charset=utf-8`.
+    pub fn from_content_type(content_type: &str) -> Option<Codec> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        [Codec::Json, Codec::Bincode, Codec::MessagePack]
+            .iter()
+            .copied()
+            .find(|codec| codec.content_type().eq_ignore_ascii_case(mime))
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> DistributionResult<Vec<u8>> {
+        match self {
+            Codec::Json => {
+                serde_json::to_vec(value).map_err(|e| DistributionError::Codec(e.into()))
+            }
+            Codec::Bincode => {
+                bincode::serialize(value).map_err(|e| DistributionError::Codec(e.into()))
+            }
+            Codec::MessagePack => {
+                rmp_serde::to_vec(value).map_err(|e| DistributionError::Codec(e.into()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> DistributionResult<T> {
+        match self {
+            Codec::Json => {
+                serde_json::from_slice(bytes).map_err(|e| DistributionError::Codec(e.into()))
+            }
+            Codec::Bincode => {
+                bincode::deserialize(bytes).map_err(|e| DistributionError::Codec(e.into()))
+            }
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| DistributionError::Codec(e.into()))
+            }
+        }
+    }
+}
+
+impl ToTokens for Codec {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        tokens.extend(match self {
+            Codec::Json => quote! { turbolift::codec::Codec::Json },
+            Codec::Bincode => quote! { turbolift::codec::Codec::Bincode },
+            Codec::MessagePack => quote! { turbolift::codec::Codec::MessagePack },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_arguments() {
+        let args = (vec![1.5f64, -2.0, 3.25], "a/b?c".to_string(), 7u32);
+        for codec in &[Codec::Json, Codec::Bincode, Codec::MessagePack] {
+            let encoded = codec.encode(&(&args.0, &args.1, &args.2)).unwrap();
+            let decoded: (Vec<f64>, String, u32) = codec.decode(&encoded).unwrap();
+            assert_eq!(decoded, args);
+            assert_eq!(Codec::from_content_type(codec.content_type()), Some(*codec));
+        }
+    }
+
+    #[test]
+    fn content_type_parameters_are_ignored() {
+        assert_eq!(
+            Codec::from_content_type("application/json; charset=utf-8"),
+            Some(Codec::Json)
+        );
+        assert_eq!(Codec::from_content_type("text/html"), None);
+    }
+}
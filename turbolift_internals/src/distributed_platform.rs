@@ -1,24 +1,395 @@
 extern crate proc_macro;
 use async_trait::async_trait;
-use std::error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{error, fmt};
+use tokio::sync::OwnedMutexGuard;
+use url::Url;
 
-pub type DistributionError = Box<dyn error::Error>;
+use crate::codec::Codec;
+use crate::resources::ResourceHints;
+use crate::retry::RetryPolicy;
+
+pub type BoxError = Box<dyn error::Error + Send + Sync + 'static>;
 pub type DistributionResult<T> = std::result::Result<T, DistributionError>;
 
-pub type ArgsString = String;
-pub type JsonResponse = String;
+/// Everything that can go wrong while distributing a function call. It is
+/// `Send + Sync + 'static`, so the futures generated by `on` can be spawned
+/// on multi-threaded runtimes and the error can be wrapped by `anyhow`.
+#[derive(Debug, thiserror::Error)]
+pub enum DistributionError {
+    /// the platform could not create the resources that serve a function.
+    #[error("could not declare {function_name}: {source}")]
+    Declare {
+        function_name: String,
+        source: BoxError,
+    },
+    /// the derived project for a function could not be built.
+    #[error("could not build {function_name}: {source}")]
+    Build {
+        function_name: String,
+        source: BoxError,
+    },
+    /// the request to a worker failed before a response was received.
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+    /// a worker did not become ready, or did not respond, in time.
+    #[error("{function_name} timed out after {waited:?} while {activity}")]
+    Timeout {
+        function_name: String,
+        activity: &'static str,
+        waited: Duration,
+    },
+    /// the worker received the request but could not return a result.
+    #[error(transparent)]
+    Remote(#[from] RemoteError),
+    /// arguments or results could not be serialized or deserialized.
+    #[error("codec error: {0}")]
+    Codec(BoxError),
+    /// any other failure in the distribution platform.
+    #[error("platform error: {0}")]
+    Platform(BoxError),
+}
+
+impl DistributionError {
+    pub fn platform<E: Into<BoxError>>(error: E) -> DistributionError {
+        DistributionError::Platform(error.into())
+    }
+
+    pub fn declare<E: Into<BoxError>>(function_name: &str, error: E) -> DistributionError {
+        DistributionError::Declare {
+            function_name: function_name.to_string(),
+            source: error.into(),
+        }
+    }
+
+    pub fn build<E: Into<BoxError>>(function_name: &str, error: E) -> DistributionError {
+        DistributionError::Build {
+            function_name: function_name.to_string(),
+            source: error.into(),
+        }
+    }
+
+    /// whether the error may go away if the call is repeated: lost
+    /// connections, timeouts, and gateway errors. Errors raised by the
+    /// function itself are never transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DistributionError::Transport(_) | DistributionError::Timeout { .. } => true,
+            DistributionError::Platform(source) => source
+                .downcast_ref::<UnexpectedResponse>()
+                .is_some_and(|response| (502..=504).contains(&response.status)),
+            _ => false,
+        }
+    }
+}
+
+/// A response that didn't come from the generated server, like a 503 from an
+/// ingress controller while a pod is being rescheduled.
+#[derive(Debug, thiserror::Error)]
+#[error("worker responded with {status}: {body}")]
+pub struct UnexpectedResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl From<std::io::Error> for DistributionError {
+    fn from(error: std::io::Error) -> Self {
+        DistributionError::platform(error)
+    }
+}
+
+impl From<url::ParseError> for DistributionError {
+    fn from(error: url::ParseError) -> Self {
+        DistributionError::platform(error)
+    }
+}
+
+impl From<kube::Error> for DistributionError {
+    fn from(error: kube::Error) -> Self {
+        DistributionError::platform(error)
+    }
+}
+
+/// A serialized value and the codec used to serialize it. Requests carry a
+/// tuple of all of a function's arguments; responses carry its result.
+#[derive(Debug, Clone)]
+pub struct Payload {
+    pub codec: Codec,
+    pub bytes: Vec<u8>,
+}
+
+impl Payload {
+    pub fn new(codec: Codec, bytes: Vec<u8>) -> Payload {
+        Payload { codec, bytes }
+    }
+
+    /// sends the payload to a worker, announcing its codec in the headers,
+    /// and returns the worker's response.
+    pub async fn post(&self, client: &reqwest::Client, url: Url) -> DistributionResult<Payload> {
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, self.codec.content_type())
+            .header(reqwest::header::ACCEPT, self.codec.content_type())
+            .body(self.bytes.clone())
+            .send()
+            .await?;
+        Payload::from_response(response, self.codec).await
+    }
+
+    /// reads a response body, using its content-type to find the codec and
+    /// falling back to the codec of the request. Unsuccessful responses are
+    /// returned as a [`RemoteError`] if the worker sent one, and otherwise as
+    /// an [`UnexpectedResponse`], e.g. when an ingress has no ready backend.
+    pub async fn from_response(
+        response: reqwest::Response,
+        request_codec: Codec,
+    ) -> DistributionResult<Payload> {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.bytes().await?;
+            return Err(match serde_json::from_slice::<RemoteError>(&body) {
+                Ok(remote_error) => remote_error.into(),
+                Err(_) => DistributionError::platform(UnexpectedResponse {
+                    status: status.as_u16(),
+                    body: String::from_utf8_lossy(&body).to_string(),
+                }),
+            });
+        }
+        let codec = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Codec::from_content_type)
+            .unwrap_or(request_codec);
+        Ok(Payload::new(codec, response.bytes().await?.to_vec()))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self) -> DistributionResult<T> {
+        self.codec.decode(&self.bytes)
+    }
+}
+
+/// Per-call settings from a function's `on` attribute. Fields that are `None`
+/// fall back to the platform's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct DispatchOptions {
+    /// how long to wait for the worker's response.
+    pub timeout: Option<Duration>,
+    /// whether calling the function more than once with the same arguments
+    /// is safe. Only idempotent functions are retried.
+    pub idempotent: bool,
+    /// overrides the number of retries in the platform's retry policy.
+    pub max_retries: Option<u32>,
+    /// for generic functions, the instantiation to call. Each instantiation
+    /// has its own route on the worker.
+    pub instantiation: Option<String>,
+    /// for functions that share a service, the function to call. The service
+    /// is declared and dispatched to by its name, and each of its functions
+    /// has its own route on the worker.
+    pub function: Option<String>,
+}
+
+impl DispatchOptions {
+    /// the path of the function's route relative to its base url, which ends
+    /// with a slash.
+    pub fn relative_route(&self) -> String {
+        [&self.function, &self.instantiation]
+            .iter()
+            .filter_map(|segment| segment.as_ref())
+            .map(|segment| format!("{}/", segment))
+            .collect()
+    }
 
+    /// the retry policy for this call, or `None` if it must not be retried.
+    pub fn retry_policy(&self, platform_policy: &RetryPolicy) -> Option<RetryPolicy> {
+        if !self.idempotent {
+            return None;
+        }
+        let mut policy = platform_policy.clone();
+        if let Some(max_retries) = self.max_retries {
+            policy.max_attempts = max_retries + 1;
+        }
+        Some(policy)
+    }
+}
+
+/// awaits `request`, failing with [`DistributionError::Timeout`] if a timeout
+/// is given and the request takes longer.
+pub async fn with_timeout<F, T>(
+    function_name: &str,
+    timeout: Option<Duration>,
+    request: F,
+) -> DistributionResult<T>
+where
+    F: Future<Output = DistributionResult<T>>,
+{
+    match timeout {
+        None => request.await,
+        Some(timeout) => tokio::time::timeout(timeout, request)
+            .await
+            .unwrap_or_else(|_elapsed| {
+                Err(DistributionError::Timeout {
+                    function_name: function_name.to_string(),
+                    activity: "waiting for a response",
+                    waited: timeout,
+                })
+            }),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemoteErrorKind {
+    /// the function panicked while running on the worker.
+    Panic,
+    /// the worker could not deserialize the arguments it received.
+    InvalidArguments,
+    /// the worker could not serialize the function's result.
+    InvalidResult,
+    /// the request's content type does not name a supported codec.
+    UnsupportedMediaType,
+}
+
+/// The error envelope sent by a worker when it can't return a result. It is
+/// always serialized as JSON, whatever codec the request used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub kind: RemoteErrorKind,
+    pub function_name: String,
+    pub run_id: String,
+    pub worker_host: String,
+    pub message: String,
+}
+
+impl RemoteError {
+    pub fn new(
+        kind: RemoteErrorKind,
+        function_name: &str,
+        run_id: &str,
+        message: String,
+    ) -> RemoteError {
+        let worker_host = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|host| host.trim().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        RemoteError {
+            kind,
+            function_name: function_name.to_string(),
+            run_id: run_id.to_string(),
+            worker_host,
+            message,
+        }
+    }
+
+    /// describes the payload of a panic caught with `std::panic::catch_unwind`.
+    pub fn from_panic(
+        function_name: &str,
+        run_id: &str,
+        panic: Box<dyn Any + Send>,
+    ) -> RemoteError {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => match panic.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "panic payload was not a string".to_string(),
+            },
+        };
+        RemoteError::new(RemoteErrorKind::Panic, function_name, run_id, message)
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} in {} on worker {} (run {}): {}",
+            self.kind, self.function_name, self.worker_host, self.run_id, self.message
+        )
+    }
+}
+
+impl error::Error for RemoteError {}
+
+/// One lock per function, held while a platform declares the function so that
+/// it is only built and started once, while different functions are declared
+/// at the same time.
+#[derive(Debug, Default)]
+pub(crate) struct DeclarationLocks(Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+impl DeclarationLocks {
+    pub(crate) async fn lock(&self, function_name: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(function_name.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+/// A platform that distributed functions run on. Platforms are handles: their
+/// clones share the functions they declared, so that generated code only locks
+/// the user's platform long enough to clone it, and calls can be declared and
+/// dispatched in parallel. Declaration state is kept behind the platform's own
+/// locks.
 #[async_trait]
-pub trait DistributionPlatform {
-    /// declare a function
-    async fn declare(&mut self, function_name: &str, project_tar: &[u8]) -> DistributionResult<()>;
+pub trait DistributionPlatform: Clone {
+    /// declare a function. Declaring a function that was already declared,
+    /// e.g. by a concurrent call, does nothing. `source_hash` identifies the
+    /// contents of `project_tar`, so that platforms can reuse what they built
+    /// for it before. `resources` holds the hints from the function's `with`
+    /// attribute; platforms ignore the hints they can't apply.
+    async fn declare(
+        &self,
+        function_name: &str,
+        project_tar: &[u8],
+        source_hash: &str,
+        resources: &ResourceHints,
+    ) -> DistributionResult<()>;
 
-    // dispatch params to a function
+    /// dispatch params to a function. Runs each time the function is called,
+    /// possibly many times at once.
     async fn dispatch(
-        &mut self,
+        &self,
         function_name: &str,
-        params: ArgsString,
-    ) -> DistributionResult<JsonResponse>;
+        params: Payload,
+        options: &DispatchOptions,
+    ) -> DistributionResult<Payload>;
 
     fn has_declared(&self, fn_name: &str) -> bool;
+
+    /// the codec used for functions that don't specify one in their `on`
+    /// attribute.
+    fn default_codec(&self) -> Codec {
+        Codec::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locks_declarations_per_function() {
+        let locks = DeclarationLocks::default();
+        let _f = locks.lock("f").await;
+        // other functions can be declared while f is
+        let _g = tokio::time::timeout(Duration::from_secs(1), locks.lock("g"))
+            .await
+            .unwrap();
+        // f can't be declared twice at once
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), locks.lock("f"))
+                .await
+                .is_err()
+        );
+    }
 }
@@ -1,61 +1,162 @@
+use std::collections::btree_map::{BTreeMap, Entry};
 use std::collections::VecDeque;
 use std::fs;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
 
 use proc_macro2::TokenStream as TokenStream2;
-use quote::ToTokens;
+use quote::{quote, ToTokens};
 use syn::spanned::Spanned;
 use tar::{Archive, Builder};
 
+use crate::attributes;
 use crate::distributed_platform::DistributionResult;
+use crate::utils::stable_hash;
 
 type TypedParams = syn::punctuated::Punctuated<syn::FnArg, syn::Token![,]>;
 type UntypedParams = syn::punctuated::Punctuated<Box<syn::Pat>, syn::Token![,]>;
 type ParamTypes = syn::punctuated::Punctuated<Box<syn::Type>, syn::Token![,]>;
 
-const IGNORED_DIRECTORIES: [&str; 3] = ["target", ".git", ".turbolift"];
+pub(crate) const IGNORED_DIRECTORIES: [&str; 3] = ["target", ".git", ".turbolift"];
 
 #[tracing::instrument]
-pub fn get_fn_item(function: TokenStream2) -> syn::ItemFn {
-    match syn::parse2(function).unwrap() {
-        syn::Item::Fn(fn_item) => fn_item,
-        _ => panic!("token stream does not represent function."),
+pub fn get_fn_item(function: TokenStream2) -> syn::Result<syn::ItemFn> {
+    match syn::parse2(function)? {
+        syn::Item::Fn(fn_item) => Ok(fn_item),
+        item => Err(syn::Error::new_spanned(
+            item,
+            "only functions can be distributed",
+        )),
     }
 }
 
-/// wraps any calls to the target function from within its own service with the return type as
-/// if the call was made from outside the service. This is one way to allow compilation while
-/// references to the target function are in the service codebase.
+/// checks that the function's signature can be distributed, reporting every
+/// part of it that can't. Receivers are checked by [`get_receiver`].
 #[tracing::instrument]
-pub fn make_dummy_function(
-    function: syn::ItemFn,
-    redirect_fn_name: &str,
-    untyped_params: UntypedParams,
-) -> syn::ItemFn {
-    let redirect_statement: syn::Stmt = syn::parse_str(&format!(
-        "return Ok({}({}));",
-        redirect_fn_name,
-        untyped_params.to_token_stream().to_string()
-    ))
-    .unwrap();
-    let output = match function.sig.output {
-        syn::ReturnType::Default => syn::ReturnType::Type(
-            Default::default(),
-            Box::new(syn::Type::Verbatim(TokenStream2::from_str("()").unwrap())),
-        ),
-        syn::ReturnType::Type(arrow_token, return_box) => syn::ReturnType::Type(
-            arrow_token,
-            Box::new(syn::Type::Verbatim(
-                TokenStream2::from_str(&format!(
-                    "turbolift::DistributionResult<{}>",
-                    return_box.to_token_stream().to_string()
+pub fn check_signature(signature: &syn::Signature) -> syn::Result<()> {
+    let mut errors = Vec::new();
+    if let Some(unsafety) = &signature.unsafety {
+        errors.push(syn::Error::new_spanned(
+            unsafety,
+            "unsafe functions can't be distributed, since the caller can't uphold their safety requirements on the worker",
+        ));
+    }
+    if let Some(abi) = &signature.abi {
+        errors.push(syn::Error::new_spanned(
+            abi,
+            "functions with an explicit ABI can't be distributed",
+        ));
+    }
+    if let Some(variadic) = &signature.variadic {
+        errors.push(syn::Error::new_spanned(
+            variadic,
+            "variadic functions can't be distributed",
+        ));
+    }
+    for fn_arg in &signature.inputs {
+        if let syn::FnArg::Typed(pat_type) = fn_arg {
+            match pat_type.pat.as_ref() {
+                syn::Pat::Ident(pat_ident)
+                    if pat_ident.by_ref.is_none() && pat_ident.subpat.is_none() => {}
+                pat => errors.push(syn::Error::new_spanned(
+                    pat,
+                    "parameters of distributed functions must be plain names, destructure them in the function body instead",
+                )),
+            }
+            match pat_type.ty.as_ref() {
+                syn::Type::Reference(reference) if reference.mutability.is_some() => {
+                    errors.push(syn::Error::new_spanned(
+                        reference,
+                        "mutable reference parameters can't be distributed, since changes made by the worker would be lost",
+                    ))
+                }
+                syn::Type::Reference(syn::TypeReference { elem, .. })
+                    if matches!(elem.as_ref(), syn::Type::Reference(_)) =>
+                {
+                    errors.push(syn::Error::new_spanned(
+                        &pat_type.ty,
+                        "references to references can't be distributed, take a single reference instead",
+                    ))
+                }
+                syn::Type::ImplTrait(impl_trait) => errors.push(syn::Error::new_spanned(
+                    impl_trait,
+                    "`impl Trait` parameters can't be distributed, since the worker has to deserialize a concrete type",
+                )),
+                _ => {}
+            }
+        }
+    }
+    if let syn::ReturnType::Type(_, return_type) = &signature.output {
+        match return_type.as_ref() {
+            syn::Type::ImplTrait(impl_trait) if iterator_item_type(&signature.output).is_none() => {
+                errors.push(syn::Error::new_spanned(
+                    impl_trait,
+                    "`impl Trait` return types other than `impl Iterator<Item = T>` can't be distributed, since the caller has to deserialize a concrete type",
                 ))
-                .unwrap(),
+            }
+            syn::Type::Reference(reference) => errors.push(syn::Error::new_spanned(
+                reference,
+                "reference return types can't be distributed, since the caller can't borrow from the worker. Return an owned value instead (e.g. `String` instead of `&str`)",
             )),
-        ),
-    };
+            _ => {}
+        }
+    }
+    match errors.into_iter().reduce(|mut error, next| {
+        error.combine(next);
+        error
+    }) {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// statements that fail to compile if a parameter or the result can't be sent
+/// between the caller and the worker, with the error pointing at its type.
+/// Generic functions are checked by the bounds from
+/// [`with_distribution_bounds`] instead.
+pub fn make_serialization_checks(signature: &syn::Signature) -> TokenStream2 {
+    if signature.generics.type_params().next().is_some() {
+        return TokenStream2::new();
+    }
+    let mut types: Vec<TokenStream2> = signature
+        .inputs
+        .iter()
+        .map(|fn_arg| match fn_arg {
+            syn::FnArg::Receiver(receiver) => quote::quote_spanned! {receiver.span()=> Self },
+            syn::FnArg::Typed(pat_type) => owned_type(&pat_type.ty).to_token_stream(),
+        })
+        .collect();
+    if let syn::ReturnType::Type(..) = &signature.output {
+        types.push(get_result_type(&signature.output));
+    }
+    let checks = types.iter().map(|ty| {
+        quote::quote_spanned! {ty.span()=>
+            distributed_types_must_be_serializable::<#ty>();
+        }
+    });
+    quote! {
+        fn distributed_types_must_be_serializable<
+            T: turbolift::serde::Serialize + turbolift::serde::de::DeserializeOwned,
+        >() {
+        }
+        #(#checks)*
+    }
+}
+
+/// wraps any calls to the target function from within its own service with the return type as
+/// if the call was made from outside the service. This is one way to allow compilation while
+/// references to the target function are in the service codebase. `redirect_call` is the
+/// call to the raw function, e.g. from [`make_redirect_call`].
+#[tracing::instrument]
+pub fn make_dummy_function(function: syn::ItemFn, redirect_call: TokenStream2) -> syn::ItemFn {
+    let redirect_statement: syn::Stmt = syn::parse2(quote! { return Ok(#redirect_call); }).unwrap();
+    let result_type = get_result_type(&function.sig.output);
+    let output = syn::ReturnType::Type(
+        Default::default(),
+        Box::new(syn::Type::Verbatim(
+            quote! { turbolift::DistributionResult<#result_type> },
+        )),
+    );
     syn::ItemFn {
         block: Box::new(syn::Block {
             brace_token: syn::token::Brace {
@@ -65,6 +166,7 @@ pub fn make_dummy_function(
         }),
         sig: syn::Signature {
             asyncness: Some(Default::default()),
+            inputs: without_mutability(function.sig.inputs),
             output,
             ..function.sig
         },
@@ -72,115 +174,945 @@ pub fn make_dummy_function(
     }
 }
 
+/// returns the function's `self` parameter, if it has one. Methods taking
+/// `&mut self` can't be distributed because the worker's changes to `self`
+/// would be lost, and explicitly typed receivers like `self: Box<Self>` aren't
+/// supported.
+#[tracing::instrument]
+pub fn get_receiver(signature: &syn::Signature) -> syn::Result<Option<syn::Receiver>> {
+    for fn_arg in &signature.inputs {
+        match fn_arg {
+            syn::FnArg::Receiver(receiver) => {
+                if receiver.reference.is_some() && receiver.mutability.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        receiver,
+                        "methods taking `&mut self` can't be distributed, since changes made by the worker would be lost",
+                    ));
+                }
+                return Ok(Some(receiver.clone()));
+            }
+            syn::FnArg::Typed(pat_type) => {
+                if let syn::Pat::Ident(pat_ident) = pat_type.pat.as_ref() {
+                    if pat_ident.ident == "self" {
+                        return Err(syn::Error::new_spanned(
+                            pat_type,
+                            "explicitly typed `self` parameters can't be distributed, use `self` or `&self`",
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// removes `mut` from a by-value `self` and from the parameters, for wrappers
+/// that only pass them along.
+pub fn without_mutability(mut typed_params: TypedParams) -> TypedParams {
+    for fn_arg in typed_params.iter_mut() {
+        match fn_arg {
+            syn::FnArg::Receiver(receiver) => {
+                if receiver.reference.is_none() {
+                    receiver.mutability = None;
+                }
+            }
+            syn::FnArg::Typed(pat_type) => {
+                if let syn::Pat::Ident(pat_ident) = pat_type.pat.as_mut() {
+                    pat_ident.mutability = None;
+                }
+            }
+        }
+    }
+    typed_params
+}
+
+/// the parameter names, without the receiver or `mut`.
 #[tracing::instrument]
 pub fn to_untyped_params(typed_params: TypedParams) -> UntypedParams {
     typed_params
         .into_iter()
-        .map(|fn_arg| match fn_arg {
-            syn::FnArg::Receiver(_r) => panic!("[to_untyped_params] receiver passed"),
-            syn::FnArg::Typed(pat_type) => pat_type.pat,
+        .filter_map(|fn_arg| match fn_arg {
+            syn::FnArg::Receiver(_) => None,
+            syn::FnArg::Typed(pat_type) => match *pat_type.pat {
+                syn::Pat::Ident(mut pat_ident) => {
+                    pat_ident.mutability = None;
+                    Some(Box::new(syn::Pat::Ident(pat_ident)))
+                }
+                pat => Some(Box::new(pat)),
+            },
         })
         .collect()
 }
 
-/// params -> {param1}/{param2}/{param3}[...]
+/// the types that the parameters are sent as, without the receiver. Reference
+/// parameters are sent as the owned value they point to.
 #[tracing::instrument]
-pub fn to_path_params(untyped_params: UntypedParams) -> String {
-    let open_bracket = "{";
-    let close_bracket = "}".to_string();
-    let path_format: Vec<String> = untyped_params
+pub fn to_param_types(typed_params: TypedParams) -> ParamTypes {
+    typed_params
         .into_iter()
-        .map(|pat| open_bracket.to_string() + &pat.into_token_stream().to_string() + &close_bracket)
-        .collect();
+        .filter_map(|fn_arg| match fn_arg {
+            syn::FnArg::Receiver(_) => None,
+            syn::FnArg::Typed(pat_type) => Some(Box::new(owned_type(&pat_type.ty))),
+        })
+        .collect()
+}
 
-    path_format.join("/")
+/// the owned type that a parameter's value is sent as: `String` for `&str`,
+/// `Vec<T>` for `&[T]`, `T` for `&T`, and the type itself otherwise.
+pub fn owned_type(ty: &syn::Type) -> syn::Type {
+    match ty {
+        syn::Type::Reference(reference) => match reference.elem.as_ref() {
+            syn::Type::Path(path) if path.qself.is_none() && path.path.is_ident("str") => {
+                syn::parse_quote_spanned! {reference.span()=> String }
+            }
+            syn::Type::Slice(slice) => {
+                let elem = &slice.elem;
+                syn::parse_quote_spanned! {reference.span()=> Vec<#elem> }
+            }
+            elem => elem.clone(),
+        },
+        ty => ty.clone(),
+    }
 }
 
+/// the arguments that the worker calls the function with: the parameter
+/// names, borrowed for reference parameters.
 #[tracing::instrument]
-pub fn to_param_types(typed_params: TypedParams) -> ParamTypes {
-    typed_params
+pub fn to_worker_args(typed_params: TypedParams) -> Vec<TokenStream2> {
+    to_untyped_params(typed_params.clone())
         .into_iter()
-        .map(|fn_arg| match fn_arg {
-            syn::FnArg::Receiver(_r) => panic!("[to_untyped_params] receiver passed"),
-            syn::FnArg::Typed(pat_type) => pat_type.ty,
+        .zip(typed_params.into_iter().filter_map(|fn_arg| match fn_arg {
+            syn::FnArg::Receiver(_) => None,
+            syn::FnArg::Typed(pat_type) => Some(pat_type.ty),
+        }))
+        .map(|(param, ty)| match *ty {
+            syn::Type::Reference(_) => quote! { &#param },
+            _ => quote! { #param },
         })
         .collect()
 }
 
-#[tracing::instrument]
-pub fn params_json_vec(untyped_params: UntypedParams) -> TokenStream2 {
-    let punc: Vec<String> = untyped_params
+/// the call from a wrapper to the raw function: `f_raw(a, b)` for functions,
+/// `Self::f_raw(a, b)` for associated functions, and `Self::f_raw(self, a, b)`
+/// for methods. Calls to async functions are awaited.
+pub fn make_redirect_call(
+    raw_function_name: &syn::Ident,
+    raw_signature: &syn::Signature,
+    in_impl: bool,
+    receiver: Option<&syn::Receiver>,
+    untyped_params: &UntypedParams,
+) -> TokenStream2 {
+    let turbofish = to_turbofish(&raw_signature.generics);
+    let params = untyped_params.iter();
+    let call = match (in_impl, receiver) {
+        (_, Some(_)) => quote! { Self::#raw_function_name #turbofish(self, #(#params),*) },
+        (true, None) => quote! { Self::#raw_function_name #turbofish(#(#params),*) },
+        (false, None) => quote! { #raw_function_name #turbofish(#(#params),*) },
+    };
+    let call = match raw_signature.asyncness {
+        Some(_) => quote! { #call.await },
+        None => call,
+    };
+    match iterator_item_type(&raw_signature.output) {
+        Some(_) => quote! { Iterator::collect::<Vec<_>>(#call) },
+        None => call,
+    }
+}
+
+/// whether the tokens refer to `Self`, in which case they only make sense
+/// inside of an impl block.
+pub fn mentions_self_type(tokens: TokenStream2) -> bool {
+    tokens.into_iter().any(|token| match token {
+        proc_macro2::TokenTree::Ident(ident) => ident == "Self",
+        proc_macro2::TokenTree::Group(group) => mentions_self_type(group.stream()),
+        _ => false,
+    })
+}
+
+/// replaces `Self` with the given type, so that types from a method's
+/// signature can be used outside of its impl block.
+pub fn replace_self_type(tokens: TokenStream2, self_type: &syn::Type) -> TokenStream2 {
+    tokens
         .into_iter()
-        .map(|pat| {
-            "turbolift::serde_json::to_string(&".to_string()
-                + &pat.into_token_stream().to_string()
-                + ").unwrap()"
+        .flat_map(|token| match token {
+            proc_macro2::TokenTree::Ident(ident) if ident == "Self" => self_type.to_token_stream(),
+            proc_macro2::TokenTree::Group(group) => {
+                let mut replaced = proc_macro2::Group::new(
+                    group.delimiter(),
+                    replace_self_type(group.stream(), self_type),
+                );
+                replaced.set_span(group.span());
+                proc_macro2::TokenTree::Group(replaced).into()
+            }
+            token => token.into(),
         })
-        .collect();
+        .collect()
+}
+
+/// params -> (param1, param2, param3,)
+#[tracing::instrument]
+pub fn to_params_tuple(untyped_params: UntypedParams) -> TokenStream2 {
+    let params = untyped_params.into_iter();
+    quote! { (#(#params,)*) }
+}
 
-    let vec_string = format!("vec![{}]", punc.join(", "));
-    TokenStream2::from_str(&vec_string).unwrap()
+/// params -> (&param1, &param2, &param3,), so that params can be serialized
+/// without moving them. Methods send `self` as the first element.
+#[tracing::instrument]
+pub fn to_borrowed_params_tuple(
+    receiver: Option<&syn::Receiver>,
+    untyped_params: UntypedParams,
+) -> TokenStream2 {
+    let receiver = receiver.map(|_| quote! { &self, });
+    let params = untyped_params.into_iter();
+    quote! { (#receiver #(&#params,)*) }
 }
 
+/// types -> (type1, type2, type3,)
 #[tracing::instrument]
-pub fn get_sanitized_file(function: &TokenStream2) -> TokenStream2 {
-    let span = function.span();
-    let path = span.source_file().path();
-    let start_line = match span.start().line {
-        0 => 0,
-        1 => 0,
-        n => n - 2,
-    }; // todo HACK func def can be more than one line
-    let end_line = span.end().line;
-
-    // generate a file with everything
-    if !path.exists() {
-        panic!("File path for the targeted function does not exist: {:?} does the compiler support getting the TokenStream from a path?", path);
-    }
-    let file_contents = std::fs::read_to_string(path).unwrap();
-
-    // remove target function
-    let target_function_removed = {
-        type Line = String;
-        let mut file_lines: Vec<Line> = file_contents.lines().map(|v| v.to_string()).collect();
-        file_lines.drain(start_line..end_line);
-        file_lines.join("\n")
+pub fn to_param_types_tuple(param_types: ParamTypes) -> TokenStream2 {
+    let types = param_types.into_iter();
+    quote! { (#(#types,)*) }
+}
+
+/// One set of concrete types for a generic function's type parameters, taken
+/// from the `instantiate` list in its `on` attribute.
+#[derive(Debug)]
+pub struct Instantiation {
+    pub types: Vec<syn::Type>,
+    /// identifies the instantiation in the worker's routes.
+    pub name: String,
+}
+
+/// checks the `instantiate` list against the function's type parameters. A
+/// function without type parameters has no instantiations.
+#[tracing::instrument(skip(function, instantiate))]
+pub fn get_instantiations(
+    function: &syn::ItemFn,
+    instantiate: &[syn::Type],
+) -> syn::Result<Vec<Instantiation>> {
+    let generics = &function.sig.generics;
+    if let Some(const_param) = generics.const_params().next() {
+        return Err(syn::Error::new_spanned(
+            const_param,
+            "functions with const generics can't be distributed",
+        ));
+    }
+    let type_params: Vec<_> = generics.type_params().collect();
+    match (type_params.len(), instantiate.is_empty()) {
+        (0, true) => return Ok(Vec::new()),
+        (0, false) => {
+            return Err(syn::Error::new_spanned(
+                &function.sig.ident,
+                "`instantiate` is only used for generic functions",
+            ))
+        }
+        (_, true) => {
+            return Err(syn::Error::new_spanned(
+                generics,
+                "generic functions need a list of the concrete types to distribute, e.g. `#[on(PLATFORM, instantiate(f64, u32))]`",
+            ))
+        }
+        _ => (),
+    }
+
+    let mut instantiations: Vec<Instantiation> = Vec::new();
+    for ty in instantiate {
+        let types = match ty {
+            _ if type_params.len() == 1 => vec![ty.clone()],
+            syn::Type::Tuple(tuple) if tuple.elems.len() == type_params.len() => {
+                tuple.elems.iter().cloned().collect()
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ty,
+                    format!(
+                        "expected a tuple with a type for each of the {} type parameters",
+                        type_params.len()
+                    ),
+                ))
+            }
+        };
+        let name = route_segment(&ty.to_token_stream().to_string());
+        if instantiations.iter().any(|other| other.name == name) {
+            return Err(syn::Error::new_spanned(ty, "duplicate instantiation"));
+        }
+        instantiations.push(Instantiation { types, name });
+    }
+    Ok(instantiations)
+}
+
+/// turns a type into something that can be used in a url, e.g.
+/// `Vec < u8 >` -> `Vec_u8`.
+fn route_segment(type_string: &str) -> String {
+    type_string
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// the name of the trait that lists a generic function's instantiations.
+pub fn instantiation_trait_ident(function_ident: &syn::Ident) -> syn::Ident {
+    quote::format_ident!("__TurboliftInstantiationsOf_{}", function_ident)
+}
+
+/// makes a trait implemented by the tuple of types of each instantiation,
+/// so that calling a generic function with types that aren't distributed is
+/// a compile error, and so that the caller can look up the route for the
+/// types it was called with.
+pub fn make_instantiation_trait(
+    function_ident: &syn::Ident,
+    vis: &syn::Visibility,
+    instantiations: &[Instantiation],
+) -> TokenStream2 {
+    let trait_ident = instantiation_trait_ident(function_ident);
+    let impls = instantiations.iter().map(|instantiation| {
+        let types = &instantiation.types;
+        let name = &instantiation.name;
+        quote! {
+            impl #trait_ident for (#(#types,)*) {
+                const INSTANTIATION: &'static str = #name;
+            }
+        }
+    });
+    quote! {
+        #[doc(hidden)]
+        #[allow(non_camel_case_types)]
+        #vis trait #trait_ident {
+            const INSTANTIATION: &'static str;
+        }
+        #(#impls)*
+    }
+}
+
+/// the function's generics with the bounds needed to call it remotely: its
+/// type parameters must be one of its instantiations, its parameters must be
+/// serializable, and its result must be deserializable. Reference parameters
+/// are serialized through the reference, so the bound is on what they point
+/// to. Functions without type parameters are returned unchanged, since their
+/// bounds hold trivially.
+pub fn with_distribution_bounds(
+    function_ident: &syn::Ident,
+    generics: &syn::Generics,
+    typed_params: &TypedParams,
+    result_type: &TokenStream2,
+) -> syn::Generics {
+    let mut generics = generics.clone();
+    let type_params: Vec<syn::Ident> = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    if type_params.is_empty() {
+        return generics;
+    }
+    let trait_ident = instantiation_trait_ident(function_ident);
+    let predicates = &mut generics.make_where_clause().predicates;
+    predicates.push(syn::parse_quote! { (#(#type_params,)*): #trait_ident });
+    for fn_arg in typed_params {
+        if let syn::FnArg::Typed(pat_type) = fn_arg {
+            let param_type = match pat_type.ty.as_ref() {
+                syn::Type::Reference(reference) => reference.elem.as_ref(),
+                param_type => param_type,
+            };
+            predicates.push(syn::parse_quote! { #param_type: turbolift::serde::Serialize });
+        }
+    }
+    predicates.push(syn::parse_quote! { #result_type: turbolift::serde::de::DeserializeOwned });
+    generics
+}
+
+/// the type parameters as a turbofish, e.g. `::<T, U>`, or nothing if there
+/// are none.
+pub fn to_turbofish(generics: &syn::Generics) -> TokenStream2 {
+    let type_params: Vec<&syn::Ident> = generics.type_params().map(|param| &param.ident).collect();
+    if type_params.is_empty() {
+        TokenStream2::new()
+    } else {
+        quote! { ::<#(#type_params),*> }
+    }
+}
+
+/// directories of a package that can hold the sources of its targets.
+const SOURCE_DIRECTORIES: [&str; 4] = ["src", "examples", "tests", "benches"];
+
+/// the directory of the package being compiled. For workspace members, this
+/// differs from the directory that rustc runs in.
+pub fn project_dir() -> PathBuf {
+    std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// the parsed `.rs` files in the package's source directories, by path
+/// relative to the project directory. The sources are scanned once per
+/// expansion of `on`, and the lookups of distributed functions share them.
+pub struct SourceFiles {
+    project_dir: PathBuf,
+    files: BTreeMap<PathBuf, syn::File>,
+}
+
+impl SourceFiles {
+    /// reads and parses the package's sources. Files that can't be read or
+    /// parsed are left out; rustc reports them when it compiles them.
+    #[tracing::instrument]
+    pub fn scan() -> syn::Result<SourceFiles> {
+        let project_dir = project_dir();
+        let mut files = BTreeMap::new();
+        for directory in SOURCE_DIRECTORIES.iter() {
+            for path in rust_files(&project_dir.join(directory)) {
+                let file = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|contents| syn::parse_file(&contents).ok());
+                if let Some(file) = file {
+                    files.insert(relative_path(&project_dir, &path)?, file);
+                }
+            }
+        }
+        Ok(SourceFiles { project_dir, files })
+    }
+
+    /// the parsed source file, given its path relative to the project directory.
+    fn get(&self, path: &Path) -> syn::Result<&syn::File> {
+        self.files.get(path).ok_or_else(|| {
+            syn::Error::new(
+                proc_macro2::Span::call_site(),
+                format!("could not read or parse {:?}", self.project_dir.join(path)),
+            )
+        })
+    }
+}
+
+/// the path of the file that the function is declared in, relative to the
+/// project directory. The package's sources are searched for a function with
+/// the same signature and body and an `on` attribute.
+fn source_path(sources: &SourceFiles, fn_item: &syn::ItemFn) -> syn::Result<PathBuf> {
+    let key = function_key(&fn_item.sig, &fn_item.block);
+    let mut paths = Vec::new();
+    for (path, file) in &sources.files {
+        if declares_distributed_function(&file.items, &key) {
+            check_not_in_inline_module(&file.items, fn_item)?;
+            paths.push(path.clone());
+        }
+    }
+    match paths.as_slice() {
+        [path] => Ok(path.clone()),
+        [] if is_nested(sources, &key) => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "functions declared inside other functions can't be distributed, move it out of its parent function",
+        )),
+        [] => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            format!(
+                "could not find this function in the sources of {:?}",
+                sources.project_dir
+            ),
+        )),
+        _ => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            format!(
+                "this function is declared identically in more than one file ({:?}), rename one of them",
+                paths
+            ),
+        )),
+    }
+}
+
+/// identifies the derived project of a distributed function, or of its
+/// service, in the cache directory, the archive, the derived package, and on
+/// the platform. Functions are identified by their crate, module path, and
+/// name, followed by a hash of their signature and impl type, so that
+/// functions with the same name in other modules, impls, or crates don't
+/// collide. Services are identified by their crate and name.
+#[tracing::instrument(skip(sources))]
+pub fn derived_project_name(
+    sources: &SourceFiles,
+    fn_item: &syn::ItemFn,
+    service: Option<&str>,
+) -> syn::Result<String> {
+    let crate_name = std::env::var("CARGO_CRATE_NAME")
+        .or_else(|_| std::env::var("CARGO_PKG_NAME").map(|name| name.replace('-', "_")))
+        .unwrap_or_else(|_| "crate".to_string());
+    if let Some(service) = service {
+        return Ok(format!("{}_{}", crate_name, service).to_lowercase());
+    }
+    let impl_type = get_impl_type(sources, fn_item)?
+        .to_token_stream()
+        .to_string();
+    let signature = fn_item.sig.to_token_stream().to_string();
+    let hash = stable_hash(vec![impl_type.as_bytes(), signature.as_bytes()]);
+    let name: Vec<String> = std::iter::once(crate_name)
+        .chain(module_path(&source_path(sources, fn_item)?))
+        .chain(std::iter::once(fn_item.sig.ident.to_string()))
+        .chain(std::iter::once(hash[..8].to_string()))
+        .collect();
+    Ok(name.join("_").to_lowercase())
+}
+
+/// the distributed functions that share the function's service, including the
+/// function itself, in a stable order. A service's functions have to be part
+/// of the same crate, so functions of the package's other targets (e.g. its
+/// examples) are left out.
+#[tracing::instrument(skip(sources))]
+pub fn find_service_functions(
+    sources: &SourceFiles,
+    fn_item: &syn::ItemFn,
+    service: &str,
+) -> syn::Result<Vec<syn::ItemFn>> {
+    let crate_root = crate_root(&source_path(sources, fn_item)?);
+    let mut functions = Vec::new();
+    for (path, file) in &sources.files {
+        if self::crate_root(path) == crate_root {
+            collect_service_functions(&file.items, service, &mut functions);
+        }
+    }
+    Ok(functions)
+}
+
+/// the free functions and methods in the items that are distributed with the
+/// given service.
+fn collect_service_functions(items: &[syn::Item], service: &str, functions: &mut Vec<syn::ItemFn>) {
+    let in_service = |attrs: &[syn::Attribute]| {
+        attrs
+            .iter()
+            .filter(|attr| attributes::is_on_attribute(attr))
+            .any(|attr| {
+                attr.parse_args::<attributes::OnArgs>()
+                    .is_ok_and(|args| args.service.as_deref() == Some(service))
+            })
     };
+    for item in items {
+        match item {
+            syn::Item::Fn(item_fn) if in_service(&item_fn.attrs) => functions.push(item_fn.clone()),
+            syn::Item::Impl(item_impl) => {
+                for item in &item_impl.items {
+                    match item {
+                        syn::ImplItem::Method(method) if in_service(&method.attrs) => functions
+                            .push(syn::ItemFn {
+                                attrs: method.attrs.clone(),
+                                vis: method.vis.clone(),
+                                sig: method.sig.clone(),
+                                block: Box::new(method.block.clone()),
+                            }),
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// identifies the crate that a source file belongs to. The package's library
+/// and main binary share a crate root in the worker project, see
+/// [`make_worker_sources`], and every other target is its own crate.
+fn crate_root(source_path: &Path) -> PathBuf {
+    if source_path.starts_with("src") && !source_path.starts_with(Path::new("src").join("bin")) {
+        PathBuf::from("src")
+    } else {
+        source_path.to_path_buf()
+    }
+}
 
-    let sanitized_string = {
-        // remove main function if it exists
-        // todo handle if the main function is decorated
-        // todo remove main function instead of just mangling it
-        let main_definition = "fn main()";
-        target_function_removed.replace(main_definition, "fn _super_main()")
+/// whether the function is declared inside another function in the
+/// package's sources.
+fn is_nested(sources: &SourceFiles, key: &(String, String)) -> bool {
+    sources.files.values().any(|file| {
+        file.items
+            .iter()
+            .any(|item| declares_nested_function(item, key))
+    })
+}
+
+/// whether an item contains a function item with the given key in the body
+/// of one of its functions.
+fn declares_nested_function(item: &syn::Item, key: &(String, String)) -> bool {
+    let in_block = |block: &syn::Block| {
+        block.stmts.iter().any(|stmt| match stmt {
+            syn::Stmt::Item(syn::Item::Fn(item_fn))
+                if function_key(&item_fn.sig, &item_fn.block) == *key =>
+            {
+                true
+            }
+            syn::Stmt::Item(item) => declares_nested_function(item, key),
+            _ => false,
+        })
     };
-    TokenStream2::from_str(&sanitized_string).unwrap()
+    match item {
+        syn::Item::Fn(item_fn) => in_block(&item_fn.block),
+        syn::Item::Impl(item_impl) => item_impl.items.iter().any(|item| match item {
+            syn::ImplItem::Method(method) => in_block(&method.block),
+            _ => false,
+        }),
+        syn::Item::Mod(syn::ItemMod {
+            content: Some((_, items)),
+            ..
+        }) => items.iter().any(|item| declares_nested_function(item, key)),
+        _ => false,
+    }
 }
 
-#[tracing::instrument]
-pub fn unpack_path_params(untyped_params: &UntypedParams) -> TokenStream2 {
-    let n_params = untyped_params.len();
-    let params: Vec<String> = (0..n_params).map(|i| format!("path.{}", i)).collect();
-    TokenStream2::from_str(&params.join(", ")).unwrap()
+/// the path of a source file relative to the project directory.
+fn relative_path(project_dir: &Path, path: &Path) -> syn::Result<PathBuf> {
+    path.strip_prefix(project_dir)
+        .map(Path::to_path_buf)
+        .map_err(|_| {
+            syn::Error::new(
+                proc_macro2::Span::call_site(),
+                format!(
+                    "{:?} is not in the project directory {:?}",
+                    path, project_dir
+                ),
+            )
+        })
+}
+
+/// the `.rs` files in a directory and its subdirectories, in a stable order.
+fn rust_files(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    entries.sort();
+    let mut files = Vec::new();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(rust_files(&path));
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// whether the items declare the function with an `on` attribute, as a free
+/// function or a method, including in inline modules.
+fn declares_distributed_function(items: &[syn::Item], key: &(String, String)) -> bool {
+    let is_distributed = |attrs: &[syn::Attribute], sig: &syn::Signature, block: &syn::Block| {
+        attrs.iter().any(attributes::is_on_attribute) && function_key(sig, block) == *key
+    };
+    items.iter().any(|item| match item {
+        syn::Item::Fn(item_fn) => is_distributed(&item_fn.attrs, &item_fn.sig, &item_fn.block),
+        syn::Item::Impl(item_impl) => item_impl.items.iter().any(|item| {
+            matches!(item, syn::ImplItem::Method(method) if is_distributed(&method.attrs, &method.sig, &method.block))
+        }),
+        syn::Item::Mod(syn::ItemMod {
+            content: Some((_, items)),
+            ..
+        }) => declares_distributed_function(items, key),
+        _ => false,
+    })
+}
+
+/// rejects functions declared in inline modules: the worker's glue is added at
+/// the top level of the function's file, outside of the module's imports and
+/// private items.
+fn check_not_in_inline_module(items: &[syn::Item], fn_item: &syn::ItemFn) -> syn::Result<()> {
+    let key = function_key(&fn_item.sig, &fn_item.block);
+    let in_inline_module = items.iter().any(|item| {
+        matches!(item, syn::Item::Mod(syn::ItemMod { content: Some((_, items)), .. }) if declares_distributed_function(items, &key))
+    });
+    if in_inline_module {
+        return Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "functions in inline modules can't be distributed, move the module into its own file",
+        ));
+    }
+    Ok(())
+}
+
+/// the path of the module that the function is declared in, e.g.
+/// `compute::kernels` for `src/compute/kernels.rs`. Functions in a crate root
+/// (`src/main.rs`, `src/lib.rs`, binaries, and examples) have an empty path.
+pub fn get_module_path(
+    sources: &SourceFiles,
+    fn_item: &syn::ItemFn,
+) -> syn::Result<Vec<syn::Ident>> {
+    Ok(module_path(&source_path(sources, fn_item)?)
+        .iter()
+        .map(|module| quote::format_ident!("{}", module))
+        .collect())
+}
+
+fn module_path(source_path: &Path) -> Vec<String> {
+    let mut components: Vec<String> = source_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if components.first().map(String::as_str) != Some("src")
+        || components.get(1).map(String::as_str) == Some("bin")
+    {
+        return Vec::new();
+    }
+    components.remove(0);
+    let file_name = components.pop().unwrap_or_default();
+    match file_name.as_str() {
+        "main.rs" | "lib.rs" if components.is_empty() => (),
+        "mod.rs" => (),
+        _ => components.push(file_name.trim_end_matches(".rs").to_string()),
+    }
+    components
+}
+
+/// finds the type of the impl block that the function is declared in, or
+/// `None` if it is a free function. The function is matched by its signature
+/// and body, since its location isn't available.
+#[tracing::instrument(skip(sources))]
+pub fn get_impl_type(
+    sources: &SourceFiles,
+    fn_item: &syn::ItemFn,
+) -> syn::Result<Option<syn::Type>> {
+    let file = sources.get(&source_path(sources, fn_item)?)?;
+    let key = function_key(&fn_item.sig, &fn_item.block);
+    let mut impls = Vec::new();
+    find_impls(&file.items, &mut |item_impl| {
+        let declares_function = item_impl.items.iter().any(|item| match item {
+            syn::ImplItem::Method(method) => function_key(&method.sig, &method.block) == key,
+            _ => false,
+        });
+        if declares_function {
+            impls.push(item_impl.clone());
+        }
+    });
+    match impls.as_slice() {
+        [] => Ok(None),
+        [item_impl] if item_impl.trait_.is_some() => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "trait methods can't be distributed, move the function into an inherent impl",
+        )),
+        [item_impl] if !item_impl.generics.params.is_empty() => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "methods of generic impl blocks can't be distributed",
+        )),
+        [item_impl] => Ok(Some(*item_impl.self_ty.clone())),
+        _ => Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "this function is declared identically in more than one impl block, rename one of them",
+        )),
+    }
+}
+
+/// identifies a function in a parsed source file by its signature and body,
+/// since its location isn't available.
+fn function_key(sig: &syn::Signature, block: &syn::Block) -> (String, String) {
+    (
+        sig.to_token_stream().to_string(),
+        block.to_token_stream().to_string(),
+    )
+}
+
+/// calls `f` for each impl block, including those in inline modules.
+fn find_impls(items: &[syn::Item], f: &mut impl FnMut(&syn::ItemImpl)) {
+    for item in items {
+        match item {
+            syn::Item::Impl(item_impl) => f(item_impl),
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, items)),
+                ..
+            }) => find_impls(items, f),
+            _ => (),
+        }
+    }
+}
+
+/// the source files of the worker project that differ from the original
+/// project, by path relative to the project directory. Each function is
+/// removed from its file, which gets the function's module glue instead. The
+/// crate root loses its `main`, gets `root_glue`, and is always written to
+/// `src/main.rs`. The modules on the way to the functions are made visible to
+/// the crate root so that `root_glue` can refer to the module glue.
+#[tracing::instrument(skip(sources))]
+pub fn make_worker_sources(
+    sources: &SourceFiles,
+    functions: &[(syn::ItemFn, TokenStream2)],
+    root_glue: TokenStream2,
+) -> syn::Result<Vec<(PathBuf, TokenStream2)>> {
+    let mut files = BTreeMap::new();
+    let mut root_path = None;
+    for (fn_item, module_glue) in functions {
+        let path = source_path(sources, fn_item)?;
+        let (file, glue) = load_source_file(sources, &mut files, &path)?;
+        remove_target_function(file, fn_item)?;
+        glue.extend(module_glue.clone());
+
+        let module_path = module_path(&path);
+        let function_root_path = match module_path.split_first() {
+            None => path,
+            Some((first_module, child_modules)) => {
+                let function_root_path = ["main.rs", "lib.rs"]
+                    .iter()
+                    .map(|root| PathBuf::from("src").join(root))
+                    .find(|root| {
+                        sources.files.get(root).is_some_and(|root| {
+                            find_module_declaration(&root.items, first_module).is_some()
+                        })
+                    })
+                    .ok_or_else(|| {
+                        syn::Error::new_spanned(
+                            &fn_item.sig,
+                            format!(
+                                "could not find `mod {};` in src/main.rs or src/lib.rs",
+                                first_module
+                            ),
+                        )
+                    })?;
+                let (root, _) = load_source_file(sources, &mut files, &function_root_path)?;
+                make_module_visible(root, first_module, fn_item)?;
+
+                let mut module_dir = PathBuf::from("src");
+                for (parent, child) in module_path.iter().zip(child_modules) {
+                    module_dir.push(parent);
+                    let parent_path = module_dir.with_extension("rs");
+                    let parent_path = if sources.files.contains_key(&parent_path) {
+                        parent_path
+                    } else {
+                        module_dir.join("mod.rs")
+                    };
+                    let (parent_file, _) = load_source_file(sources, &mut files, &parent_path)?;
+                    make_module_visible(parent_file, child, fn_item)?;
+                }
+                function_root_path
+            }
+        };
+        match &root_path {
+            Some(root_path) if *root_path != function_root_path => {
+                return Err(syn::Error::new_spanned(
+                    &fn_item.sig,
+                    format!(
+                        "the functions of a service must be part of the same crate, but this one is in {:?} and another is in {:?}",
+                        function_root_path, root_path
+                    ),
+                ))
+            }
+            _ => root_path = Some(function_root_path),
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|(path, (file, glue))| {
+            if Some(&path) == root_path.as_ref() {
+                let file = remove_main(file);
+                (
+                    PathBuf::from("src").join("main.rs"),
+                    quote! { #file #glue #root_glue },
+                )
+            } else {
+                (path, quote! { #file #glue })
+            }
+        })
+        .collect())
+}
+
+/// the source file at the path, with the glue appended to it so far. Each file
+/// is only copied from the sources once, so that changes from several functions
+/// add up.
+fn load_source_file<'a>(
+    sources: &SourceFiles,
+    files: &'a mut BTreeMap<PathBuf, (syn::File, TokenStream2)>,
+    path: &Path,
+) -> syn::Result<&'a mut (syn::File, TokenStream2)> {
+    match files.entry(path.to_path_buf()) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => Ok(entry.insert((sources.get(path)?.clone(), TokenStream2::new()))),
+    }
+}
+
+fn remove_target_function(file: &mut syn::File, fn_item: &syn::ItemFn) -> syn::Result<()> {
+    if remove_function(&mut file.items, &function_key(&fn_item.sig, &fn_item.block)) == 0 {
+        return Err(syn::Error::new_spanned(
+            &fn_item.sig,
+            "could not find this function in its source file",
+        ));
+    }
+    Ok(())
+}
+
+fn remove_main(mut file: syn::File) -> syn::File {
+    file.items
+        .retain(|item| !matches!(item, syn::Item::Fn(item_fn) if item_fn.sig.ident == "main"));
+    file
+}
+
+fn find_module_declaration(items: &[syn::Item], module: &str) -> Option<usize> {
+    items.iter().position(|item| {
+        matches!(item, syn::Item::Mod(item_mod) if item_mod.ident == module && item_mod.content.is_none())
+    })
+}
+
+/// makes a private `mod module;` declaration `pub(crate)`.
+fn make_module_visible(
+    file: &mut syn::File,
+    module: &str,
+    fn_item: &syn::ItemFn,
+) -> syn::Result<()> {
+    let position = find_module_declaration(&file.items, module).ok_or_else(|| {
+        syn::Error::new_spanned(
+            &fn_item.sig,
+            format!("could not find the declaration of the module `{}`", module),
+        )
+    })?;
+    if let syn::Item::Mod(item_mod) = &mut file.items[position] {
+        if let syn::Visibility::Inherited = item_mod.vis {
+            item_mod.vis = syn::parse_quote!(pub(crate));
+        }
+    }
+    Ok(())
+}
+
+/// removes every declaration of the function with the given key, including
+/// methods and declarations in inline modules. Returns how many were removed.
+fn remove_function(items: &mut Vec<syn::Item>, key: &(String, String)) -> usize {
+    let mut removed = 0;
+    items.retain(|item| match item {
+        syn::Item::Fn(item_fn) if function_key(&item_fn.sig, &item_fn.block) == *key => {
+            removed += 1;
+            false
+        }
+        _ => true,
+    });
+    for item in items.iter_mut() {
+        match item {
+            syn::Item::Impl(item_impl) => item_impl.items.retain(|item| match item {
+                syn::ImplItem::Method(method)
+                    if function_key(&method.sig, &method.block) == *key =>
+                {
+                    removed += 1;
+                    false
+                }
+                _ => true,
+            }),
+            syn::Item::Mod(syn::ItemMod {
+                content: Some((_, items)),
+                ..
+            }) => removed += remove_function(items, key),
+            _ => (),
+        }
+    }
+    removed
 }
 
 #[tracing::instrument]
-pub fn make_compressed_proj_src(dir: &Path) -> Vec<u8> {
+pub fn make_compressed_proj_src(dir: &Path) -> std::io::Result<Vec<u8>> {
     let cursor = Cursor::new(Vec::new());
     let mut archive = Builder::new(cursor);
 
-    let mut entries: VecDeque<(PathBuf, std::fs::DirEntry)> = fs::read_dir(dir)
-        .unwrap()
+    let tar_project_base_dir = dir.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} has no directory name", dir),
+        )
+    })?;
+    let mut entries: VecDeque<(PathBuf, std::fs::DirEntry)> = fs::read_dir(dir)?
         .filter_map(Result::ok)
-        .map(|entry| (dir.file_name().unwrap().into(), entry))
+        .map(|entry| (tar_project_base_dir.into(), entry))
         .collect(); // ignore read errors
 
-    let tar_project_base_dir = dir.file_name().unwrap();
-
-    archive.append_dir(tar_project_base_dir, dir).unwrap();
-    while !entries.is_empty() {
-        let (entry_parent, entry) = entries.pop_front().unwrap();
-        if entry.metadata().unwrap().is_dir()
+    archive.append_dir(tar_project_base_dir, dir)?;
+    while let Some((entry_parent, entry)) = entries.pop_front() {
+        if entry.metadata()?.is_dir()
             && (IGNORED_DIRECTORIES // todo could there be cases where removing .git messes up a dependency?
                 .contains(&entry.file_name().to_str().unwrap_or("")))
         {
@@ -193,24 +1125,21 @@ pub fn make_compressed_proj_src(dir: &Path) -> Vec<u8> {
                 if IGNORED_DIRECTORIES.contains(&entry.file_name().to_str().unwrap_or("")) {
                     // don't include any target or .turbolift directories
                 } else {
-                    archive
-                        .append_dir(&entry_path_with_parent, entry.path())
-                        .unwrap();
+                    archive.append_dir(&entry_path_with_parent, entry.path())?;
                     entries.extend(
-                        fs::read_dir(entry.path())
-                            .unwrap()
+                        fs::read_dir(entry.path())?
                             .filter_map(Result::ok)
                             .map(|child| (entry_parent.join(entry.file_name()), child)),
                     )
                 }
             } else {
-                let mut f = fs::File::open(entry.path()).unwrap();
-                archive.append_file(entry_path_with_parent, &mut f).unwrap();
+                let mut f = fs::File::open(entry.path())?;
+                archive.append_file(entry_path_with_parent, &mut f)?;
             }
         }
     }
-    archive.finish().unwrap();
-    archive.into_inner().unwrap().into_inner()
+    archive.finish()?;
+    Ok(archive.into_inner()?.into_inner())
 }
 
 #[tracing::instrument(skip(src))]
@@ -220,11 +1149,200 @@ pub fn decompress_proj_src(src: &[u8], dest: &Path) -> DistributionResult<()> {
     Ok(archive.unpack(dest)?)
 }
 
+/// the type that the result is sent as. Functions returning
+/// `impl Iterator<Item = T>` send the collected `Vec<T>`.
 /// assumes input is a function, not a closure.
 #[tracing::instrument]
 pub fn get_result_type(output: &syn::ReturnType) -> TokenStream2 {
     match output {
-        syn::ReturnType::Default => TokenStream2::from_str("()").unwrap(),
-        syn::ReturnType::Type(_right_arrow, boxed_type) => boxed_type.to_token_stream(),
+        syn::ReturnType::Default => quote! { () },
+        syn::ReturnType::Type(_right_arrow, boxed_type) => match iterator_item_type(output) {
+            Some(item_type) => quote::quote_spanned! {boxed_type.span()=> Vec<#item_type> },
+            None => boxed_type.to_token_stream(),
+        },
+    }
+}
+
+/// the item type of an `impl Iterator<Item = T>` return type.
+pub fn iterator_item_type(output: &syn::ReturnType) -> Option<&syn::Type> {
+    let bounds = match output {
+        syn::ReturnType::Type(_, return_type) => match return_type.as_ref() {
+            syn::Type::ImplTrait(impl_trait) => &impl_trait.bounds,
+            _ => return None,
+        },
+        syn::ReturnType::Default => return None,
+    };
+    bounds.iter().find_map(|bound| match bound {
+        syn::TypeParamBound::Trait(trait_bound) => {
+            let segment = trait_bound.path.segments.last()?;
+            if segment.ident != "Iterator" {
+                return None;
+            }
+            match &segment.arguments {
+                syn::PathArguments::AngleBracketed(arguments) => {
+                    arguments.args.iter().find_map(|argument| match argument {
+                        syn::GenericArgument::Binding(binding) if binding.ident == "Item" => {
+                            Some(&binding.ty)
+                        }
+                        _ => None,
+                    })
+                }
+                _ => None,
+            }
+        }
+        syn::TypeParamBound::Lifetime(_) => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_file() {
+        let file = syn::parse_file(
+            r#"
+            use std::io;
+
+            /// adds one,
+            /// distributed.
+            #[on(
+                K8S,
+                timeout = "10s"
+            )]
+            fn add_one(a: u32) -> u32 {
+                a + 1
+            }
+
+            struct Number(u32);
+
+            impl Number {
+                #[on(K8S)]
+                fn double(&self) -> u32 {
+                    self.0 * 2
+                }
+            }
+
+            #[tokio::main]
+            async fn main() -> io::Result<()> {
+                Ok(())
+            }
+            "#,
+        )
+        .unwrap();
+        let function: syn::ItemFn = syn::parse_str("fn add_one(a: u32) -> u32 { a + 1 }").unwrap();
+        let mut sanitized = file.clone();
+        remove_target_function(&mut sanitized, &function).unwrap();
+        let sanitized = remove_main(sanitized);
+        assert_eq!(sanitized.items.len(), 3);
+        assert!(!sanitized.to_token_stream().to_string().contains("main"));
+
+        let method: syn::ItemFn = syn::parse_str("fn double(&self) -> u32 { self.0 * 2 }").unwrap();
+        let mut sanitized = file.clone();
+        remove_target_function(&mut sanitized, &method).unwrap();
+        let sanitized = sanitized.to_token_stream().to_string();
+        assert!(sanitized.contains("add_one") && !sanitized.contains("double"));
+
+        let missing: syn::ItemFn = syn::parse_str("fn add_two(a: u32) -> u32 { a + 2 }").unwrap();
+        assert!(remove_target_function(&mut file.clone(), &missing).is_err());
+    }
+
+    #[test]
+    fn rejects_functions_in_inline_modules() {
+        let file = syn::parse_file(
+            r#"
+            #[on(K8S)]
+            fn add_one(a: u32) -> u32 {
+                a + 1
+            }
+
+            mod inline {
+                #[on(K8S)]
+                fn add_two(a: u32) -> u32 {
+                    a + 2
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let check = |function: &str| {
+            check_not_in_inline_module(&file.items, &syn::parse_str(function).unwrap())
+        };
+        assert!(check("fn add_one(a: u32) -> u32 { a + 1 }").is_ok());
+        assert!(check("fn add_two(a: u32) -> u32 { a + 2 }").is_err());
+    }
+
+    #[test]
+    fn finds_module_paths() {
+        let module_path = |path: &str| module_path(Path::new(path)).join("::");
+        assert_eq!(module_path("src/main.rs"), "");
+        assert_eq!(module_path("src/bin/tool.rs"), "");
+        assert_eq!(module_path("examples/demo.rs"), "");
+        assert_eq!(module_path("src/compute.rs"), "compute");
+        assert_eq!(module_path("src/compute/mod.rs"), "compute");
+        assert_eq!(module_path("src/compute/kernels.rs"), "compute::kernels");
+    }
+
+    #[test]
+    fn finds_crate_roots() {
+        let crate_root = |path: &str| crate_root(Path::new(path));
+        assert_eq!(
+            crate_root("src/main.rs"),
+            crate_root("src/compute/kernels.rs")
+        );
+        assert_eq!(crate_root("src/lib.rs"), crate_root("src/compute.rs"));
+        assert_ne!(crate_root("src/bin/tool.rs"), crate_root("src/main.rs"));
+        assert_ne!(
+            crate_root("examples/demo.rs"),
+            crate_root("examples/other.rs")
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_signatures() {
+        let check = |signature: &str| {
+            check_signature(&syn::parse_str(signature).unwrap()).map_err(|e| e.into_iter().count())
+        };
+        assert_eq!(check("fn add(mut a: u32, b: Vec<u8>) -> u32"), Ok(()));
+        assert_eq!(check("fn score(doc: &str, weights: &[f32]) -> f32"), Ok(()));
+        assert_eq!(check("unsafe fn add((a, b): (u32, u32)) -> u32"), Err(2));
+        assert_eq!(check("fn len(s: &mut str, _: u32) -> impl Clone"), Err(3));
+        assert_eq!(check("fn first(s: &&str) -> &str"), Err(2));
+        assert_eq!(
+            check("fn words(s: String) -> impl Iterator<Item = String>"),
+            Ok(())
+        );
+        assert_eq!(
+            check("fn words(s: String) -> impl IntoIterator<Item = String>"),
+            Err(1)
+        );
+    }
+
+    #[test]
+    fn sends_owned_parameters() {
+        let param_types = |signature: &str| {
+            let signature: syn::Signature = syn::parse_str(signature).unwrap();
+            to_param_types(signature.inputs)
+                .to_token_stream()
+                .to_string()
+        };
+        assert_eq!(
+            param_types("fn score(doc: &str, weights: &[f32], id: &'a Id, n: u8)"),
+            "String , Vec < f32 > , Id , u8"
+        );
+    }
+
+    #[test]
+    fn sends_collected_iterators() {
+        let result_type = |signature: &str| {
+            let signature: syn::Signature = syn::parse_str(signature).unwrap();
+            get_result_type(&signature.output).to_string()
+        };
+        assert_eq!(
+            result_type("fn words(s: &str) -> impl Iterator<Item = String> + '_"),
+            "Vec < String >"
+        );
+        assert_eq!(result_type("fn count(s: &str) -> usize"), "usize");
+        assert_eq!(result_type("fn log(s: &str)"), "()");
     }
 }
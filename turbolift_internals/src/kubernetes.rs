@@ -2,6 +2,8 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use derivative::Derivative;
@@ -10,20 +12,28 @@ use k8s_openapi::api::core::v1::Service;
 use kube::api::{Api, PostParams};
 use kube::Client;
 use regex::Regex;
-use tokio::time::{sleep, Duration};
 use tokio_compat_02::FutureExt;
 use url::Url;
 use uuid::Uuid;
 
+use crate::build_project::remove_outdated_artifacts;
+use crate::codec::Codec;
 use crate::distributed_platform::{
-    ArgsString, DistributionPlatform, DistributionResult, JsonResponse,
+    with_timeout, DeclarationLocks, DispatchOptions, DistributionError, DistributionPlatform,
+    DistributionResult, Payload,
 };
-use crate::utils::{DEBUG_FLAG, RELEASE_FLAG};
+use crate::readiness::{poll_until_ready, wait_for_health_probe, ReadinessPolicy};
+use crate::resources::ResourceHints;
+use crate::retry::{with_retries, RetryPolicy};
+use crate::utils::{stable_hash, DEBUG_FLAG, RELEASE_FLAG};
 use crate::CACHE_PATH;
 
 const TURBOLIFT_K8S_NAMESPACE: &str = "default";
 type ImageTag = String;
-type DeployContainerFunction = Box<dyn Fn(String) -> anyhow::Result<String> + Send + 'static>;
+type DeployContainerFunction =
+    Box<dyn Fn(String) -> anyhow::Result<String> + Send + Sync + 'static>;
+type SharedDeployContainerFunction =
+    Arc<dyn Fn(String) -> anyhow::Result<String> + Send + Sync + 'static>;
 
 pub const CONTAINER_PORT: i32 = 5678;
 pub const SERVICE_PORT: i32 = 5678;
@@ -34,20 +44,24 @@ pub const TARGET_ARCHITECTURE: Option<&str> = None;
 //   span features to extract functions into services. When we can enable statically linked
 //   targets, we can use the multi-stage build path and significantly reduce the size.
 
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 /// `K8s` is the interface for turning rust functions into autoscaling microservices
 /// using turbolift. It requires docker and kubernetes / kubectl to already be setup on the
-/// device at runtime.
+/// device at runtime. Clones share their deployments, so callers can release the lock
+/// on the platform before declaring or dispatching.
 ///
 /// Access to the kubernetes cluster must be inferrable from the env variables at runtime
 /// per kube-rs's
 /// [try_default()](https://docs.rs/kube/0.56.0/kube/client/struct.Client.html#method.try_default).
 pub struct K8s {
     max_scale_n: u32,
-    fn_names_to_ips: HashMap<String, Url>,
+    deployments: Arc<Deployments>,
     request_client: reqwest::Client,
-    run_id: Uuid,
+    readiness_policy: ReadinessPolicy,
+    codec: Codec,
+    request_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
 
     #[derivative(Debug = "ignore")]
     /// A function called after the image is built locally via docker. deploy_container
@@ -59,7 +73,15 @@ pub struct K8s {
     /// Some examples of how this function can be implemented: uploading the image to
     /// the cluster's private registry, uploading the image publicly to docker hub
     /// (if the image is not sensitive), loading the image into KinD in tests.
-    deploy_container: DeployContainerFunction,
+    deploy_container: SharedDeployContainerFunction,
+}
+
+/// the functions deployed by a `K8s` and its clones during a run.
+#[derive(Debug)]
+struct Deployments {
+    run_id: Uuid,
+    declaring: DeclarationLocks,
+    fn_names_to_ips: RwLock<HashMap<String, Url>>,
 }
 
 impl K8s {
@@ -76,23 +98,118 @@ impl K8s {
             panic!("max < 1 while instantiating k8s (value: {})", max)
         }
         K8s {
-            deploy_container,
+            deploy_container: Arc::from(deploy_container),
             max_scale_n: max,
-            fn_names_to_ips: HashMap::new(),
+            deployments: Arc::new(Deployments {
+                run_id: Uuid::new_v4(),
+                declaring: Default::default(),
+                fn_names_to_ips: Default::default(),
+            }),
             request_client: reqwest::Client::new(),
-            run_id: Uuid::new_v4(),
+            readiness_policy: Default::default(),
+            codec: Default::default(),
+            request_timeout: None,
+            retry_policy: Default::default(),
         }
     }
+
+    /// sets how long `declare` waits for a new deployment to have a ready
+    /// replica and for its ingress route to answer the health probe.
+    pub fn with_readiness_policy(mut self, readiness_policy: ReadinessPolicy) -> K8s {
+        self.readiness_policy = readiness_policy;
+        self
+    }
+
+    /// sets the codec for functions that don't choose one in their `on`
+    /// attribute.
+    pub fn with_codec(mut self, codec: Codec) -> K8s {
+        self.codec = codec;
+        self
+    }
+
+    /// sets how long to wait for a response from a function that doesn't set
+    /// its own `timeout` in its `on` attribute. By default, there's no limit.
+    pub fn with_timeout(mut self, timeout: Duration) -> K8s {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// sets how calls to functions marked `idempotent` are retried after a
+    /// transient failure, like a 503 from the ingress while a pod restarts.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> K8s {
+        self.retry_policy = retry_policy;
+        self
+    }
 }
 
 fn sanitize_function_name(function_name: &str) -> String {
     function_name.to_string().replace("_", "-")
 }
 
+/// the longest name that fits in an app name: kubernetes limits container
+/// and service names to 63 characters, the longest of them is
+/// `{app_name}-service`, and the run id takes 37.
+const MAX_APP_NAME_PREFIX: usize = 18;
+
+/// names the resources of a function for a run. Long function names are
+/// shortened to their start and a hash of the whole name, so that names that
+/// only differ in the part that is cut off stay distinct.
+fn app_name(function_name: &str, run_id: &Uuid) -> String {
+    let name = sanitize_function_name(function_name);
+    let name = if name.chars().count() > MAX_APP_NAME_PREFIX {
+        let hash = stable_hash(std::iter::once(function_name.as_bytes()));
+        let start: String = name.chars().take(MAX_APP_NAME_PREFIX - 9).collect();
+        format!("{}-{}", start.trim_end_matches('-'), &hash[..8])
+    } else {
+        name
+    };
+    format!("{}-{}", name, run_id)
+}
+
+/// requests the hinted cpu and memory for the container. Memory is also used
+/// as the limit, so that a function that outgrows its hint is restarted
+/// instead of starving its neighbours; cpu is left unlimited.
+fn container_resources(resources: &ResourceHints) -> serde_json::Value {
+    let mut requests = serde_json::Map::new();
+    let mut limits = serde_json::Map::new();
+    if let Some(cpu) = &resources.cpu {
+        requests.insert("cpu".into(), cpu.clone().into());
+    }
+    if let Some(memory) = &resources.memory {
+        requests.insert("memory".into(), memory.clone().into());
+        limits.insert("memory".into(), memory.clone().into());
+    }
+    serde_json::json!({
+        "requests": requests,
+        "limits": limits,
+    })
+}
+
+fn container_env(resources: &ResourceHints) -> serde_json::Value {
+    resources
+        .worker_env()
+        .iter()
+        .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+        .collect()
+}
+
 #[async_trait]
 impl DistributionPlatform for K8s {
     #[tracing::instrument(skip(project_tar))]
-    async fn declare(&mut self, function_name: &str, project_tar: &[u8]) -> DistributionResult<()> {
+    async fn declare(
+        &self,
+        function_name: &str,
+        project_tar: &[u8],
+        source_hash: &str,
+        resources: &ResourceHints,
+    ) -> DistributionResult<()> {
+        let _declaring = self.deployments.declaring.lock(function_name).await;
+        // another call may have declared the function while we waited for the lock
+        if self.has_declared(function_name) {
+            return Ok(());
+        }
+        let run_id = self.deployments.run_id;
+
         // connect to cluster. tries in-cluster configuration first, then falls back to kubeconfig file.
         let deployment_client = Client::try_default().compat().await?;
         let deployments: Api<Deployment> =
@@ -101,12 +218,13 @@ impl DistributionPlatform for K8s {
         let services: Api<Service> = Api::namespaced(service_client, TURBOLIFT_K8S_NAMESPACE);
 
         // generate image & push
-        let app_name = format!("{}-{}", sanitize_function_name(function_name), self.run_id);
+        let app_name = app_name(function_name, &run_id);
         let container_name = format!("{}-app", app_name);
         let deployment_name = format!("{}-deployment", app_name);
         let service_name = format!("{}-service", app_name);
         let ingress_name = format!("{}-ingress", app_name);
-        let tag_in_reg = make_image(self, &app_name, function_name, project_tar)?;
+        let tag_in_reg = make_image(self, &app_name, function_name, source_hash, project_tar)
+            .map_err(|e| DistributionError::build(function_name, e))?;
 
         // make deployment
         let deployment_json = serde_json::json!({
@@ -115,7 +233,7 @@ impl DistributionPlatform for K8s {
             "metadata": {
                 "name": deployment_name,
                 "labels": {
-                    "turbolift_run_id": self.run_id.to_string()
+                    "turbolift_run_id": run_id.to_string()
                 }
             },
             "spec": {
@@ -124,13 +242,13 @@ impl DistributionPlatform for K8s {
                         "app": app_name
                     }
                 },
-                "replicas": 1,
+                "replicas": resources.replicas.unwrap_or(1),
                 "template": {
                     "metadata": {
                      "name": format!("{}-app", app_name),
                      "labels": {
                        "app": app_name,
-                       "turbolift_run_id": self.run_id.to_string(),
+                       "turbolift_run_id": run_id.to_string(),
                      }
                     },
                     "spec": {
@@ -138,6 +256,8 @@ impl DistributionPlatform for K8s {
                             {
                                 "name": container_name,
                                 "image": tag_in_reg,
+                                "resources": container_resources(resources),
+                                "env": container_env(resources),
                                 "startupProbe": {
                                     "httpGet": {
                                         "path": "/health-probe",
@@ -165,11 +285,13 @@ impl DistributionPlatform for K8s {
                 }
             }
         });
-        let deployment = serde_json::from_value(deployment_json)?;
+        let deployment = serde_json::from_value(deployment_json)
+            .map_err(|e| DistributionError::declare(function_name, e))?;
         deployments
             .create(&PostParams::default(), &deployment)
             .compat()
-            .await?;
+            .await
+            .map_err(|e| DistributionError::declare(function_name, e))?;
 
         // make service pointing to deployment
         let service_json = serde_json::json!({
@@ -178,7 +300,7 @@ impl DistributionPlatform for K8s {
             "metadata": {
                 "name": service_name,
                 "labels": {
-                    "turbolift_run_id": self.run_id.to_string(),
+                    "turbolift_run_id": run_id.to_string(),
                 }
             },
             "spec": {
@@ -191,11 +313,13 @@ impl DistributionPlatform for K8s {
                 }]
             }
         });
-        let service = serde_json::from_value(service_json)?;
+        let service = serde_json::from_value(service_json)
+            .map_err(|e| DistributionError::declare(function_name, e))?;
         services
             .create(&PostParams::default(), &service)
             .compat()
-            .await?;
+            .await
+            .map_err(|e| DistributionError::declare(function_name, e))?;
 
         // make ingress pointing to service
         let ingress = serde_json::json!({
@@ -204,7 +328,7 @@ impl DistributionPlatform for K8s {
             "metadata": {
                 "name": ingress_name,
                 "labels": {
-                    "turbolift_run_id": self.run_id.to_string(),
+                    "turbolift_run_id": run_id.to_string(),
                 }
             },
             "spec": {
@@ -213,7 +337,7 @@ impl DistributionPlatform for K8s {
                         "http": {
                             "paths": [
                                 {
-                                    "path": format!("/{}/{}", function_name, self.run_id),
+                                    "path": format!("/{}/{}", function_name, run_id),
                                     "pathType": "Prefix",
                                     "backend": {
                                         "service" : {
@@ -241,71 +365,117 @@ impl DistributionPlatform for K8s {
             .expect("not able to write to ingress apply stdin")
             .write_all(ingress.to_string().as_bytes())?;
         if !apply_ingress_child.wait()?.success() {
-            panic!(
-                "failed to apply ingress: {}\nis ingress enabled on this cluster?",
-                ingress.to_string()
-            )
+            return Err(DistributionError::declare(
+                function_name,
+                format!(
+                    "failed to apply ingress: {}\nis ingress enabled on this cluster?",
+                    ingress
+                ),
+            ));
         }
 
         let ingress_ip = format!(
             "http://localhost:{}/{}/{}/",
-            EXTERNAL_PORT, function_name, self.run_id
+            EXTERNAL_PORT, function_name, run_id
         ); // we assume for now that the ingress is exposed on localhost
 
         if self.max_scale_n > 1 {
             // set autoscale
+            let min_replicas = resources.replicas.unwrap_or(1);
             let scale_args = format!(
-                "autoscale deployment {} --max={}",
-                deployment_name, self.max_scale_n
+                "autoscale deployment {} --min={} --max={}",
+                deployment_name,
+                min_replicas,
+                self.max_scale_n.max(min_replicas)
             );
             let scale_status = Command::new("kubectl")
                 .args(scale_args.as_str().split(' '))
                 .status()?;
 
             if !scale_status.success() {
-                return Err(anyhow::anyhow!(
-                    "autoscale error: error code: {:?}",
-                    scale_status.code()
-                )
-                .into());
+                return Err(DistributionError::declare(
+                    function_name,
+                    format!("autoscale error: error code: {:?}", scale_status.code()),
+                ));
                 // ^ todo attach error context from child
             }
         }
 
-        sleep(Duration::from_secs(90)).await;
-        // todo make sure that the pod and service were correctly started before returning
-        // todo implement the check on whether the service is running / pod failed
-
-        self.fn_names_to_ips
-            .insert(function_name.to_string(), Url::from_str(&ingress_ip)?);
+        // wait for a pod to pass its readiness probe, then for the ingress to route to it
+        poll_until_ready(function_name, &self.readiness_policy, || {
+            let deployments = &deployments;
+            let deployment_name = &deployment_name;
+            async move {
+                let deployment = deployments.get(deployment_name).compat().await?;
+                let ready_replicas = deployment
+                    .status
+                    .and_then(|status| status.ready_replicas)
+                    .unwrap_or(0);
+                Ok(ready_replicas > 0)
+            }
+        })
+        .await?;
+        let ingress_url = Url::from_str(&ingress_ip)?;
+        wait_for_health_probe(
+            &self.request_client,
+            function_name,
+            &ingress_url.join("health-probe")?,
+            &self.readiness_policy,
+        )
+        .await?;
+
+        self.deployments
+            .fn_names_to_ips
+            .write()
+            .unwrap()
+            .insert(function_name.to_string(), ingress_url);
         Ok(())
     }
 
-    #[tracing::instrument]
+    #[tracing::instrument(skip(params))]
     async fn dispatch(
-        &mut self,
+        &self,
         function_name: &str,
-        params: ArgsString,
-    ) -> DistributionResult<JsonResponse> {
+        params: Payload,
+        options: &DispatchOptions,
+    ) -> DistributionResult<Payload> {
         // request from server
-        let service_base_url = self.fn_names_to_ips.get(function_name).unwrap();
-        let args = format!("./{}", params);
-        let query_url = service_base_url.join(&args)?;
+        let query_url = self
+            .deployments
+            .fn_names_to_ips
+            .read()
+            .unwrap()
+            .get(function_name)
+            .ok_or_else(|| {
+                DistributionError::platform(format!("{} has not been declared", function_name))
+            })?
+            .join(&options.relative_route())?;
         tracing::info!(url = query_url.as_str(), "sending dispatch request");
-        Ok(self
-            .request_client
-            .get(query_url)
-            .send()
-            .compat()
-            .await?
-            .text()
-            .compat()
-            .await?)
+        let timeout = options.timeout.or(self.request_timeout);
+        let retry_policy = options.retry_policy(&self.retry_policy);
+        with_retries(function_name, retry_policy.as_ref(), || {
+            with_timeout(
+                function_name,
+                timeout,
+                params
+                    .post(&self.request_client, query_url.clone())
+                    .compat(),
+            )
+        })
+        .await
     }
 
     #[tracing::instrument]
     fn has_declared(&self, fn_name: &str) -> bool {
-        self.fn_names_to_ips.contains_key(fn_name)
+        self.deployments
+            .fn_names_to_ips
+            .read()
+            .unwrap()
+            .contains_key(fn_name)
+    }
+
+    fn default_codec(&self) -> Codec {
+        self.codec
     }
 }
 
@@ -313,15 +483,22 @@ lazy_static! {
     static ref PORT_RE: Regex = Regex::new(r"0\.0\.0\.0:(\d+)->").unwrap();
 }
 
+/// builds and deploys the image of a function, unless an image built from the
+/// same source was already deployed. The deployed tag is recorded in the cache
+/// directory so that later runs can reuse it.
 #[tracing::instrument(skip(project_tar))]
 fn make_image(
     k8s: &K8s,
     app_name: &str,
     function_name: &str,
+    source_hash: &str,
     project_tar: &[u8],
 ) -> anyhow::Result<ImageTag> {
-    // todo: we should add some random stuff to the function_name to avoid collisions and figure
-    // out when to overwrite vs not.
+    let deployed_tag_path = CACHE_PATH.join(format!("{}_{}_image", function_name, source_hash));
+    if let Ok(deployed_tag) = std::fs::read_to_string(&deployed_tag_path) {
+        tracing::info!("reusing image built from the same source");
+        return Ok(deployed_tag);
+    }
 
     tracing::info!("making image");
     // set up directory and dockerfile
@@ -341,7 +518,7 @@ RUN ln -snf /usr/share/zoneinfo/$TZ /etc/localtime && echo $TZ > /etc/timezone
 RUN apt-get update && apt-get install -y curl gcc libssl-dev pkg-config && rm -rf /var/lib/apt/lists/*
 
 # install rustup
-RUN curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --default-toolchain nightly
+RUN curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --default-toolchain stable
 ENV PATH=/root/.cargo/bin:$PATH
 
 # copy tar file
@@ -354,7 +531,6 @@ RUN cat {tar_file_name} | tar xvf -
 WORKDIR {function_name}
 
 # build and run according to compilation scheme
-ENV RUSTFLAGS='--cfg procmacro2_semver_exempt'
 {compilation_scheme}",
         function_name=function_name,
         tar_file_name=tar_file_name,
@@ -385,7 +561,7 @@ CMD [\"./{function_name}\", \"0.0.0.0:{container_port}\"]",
     );
     std::fs::write(&dockerfile_path, docker_file)?;
     std::fs::write(&tar_path, project_tar)?;
-    let unique_tag = format!("{}:turbolift", app_name);
+    let unique_tag = format!("{}:{}", sanitize_function_name(function_name), source_hash);
 
     let result = (|| {
         // build image
@@ -408,17 +584,21 @@ CMD [\"./{function_name}\", \"0.0.0.0:{container_port}\"]",
     // always remove the build directory, even on build error
     std::fs::remove_dir_all(build_dir_canonical)?;
 
-    result.and((k8s.deploy_container)(unique_tag))
+    let deployed_tag = result.and_then(|unique_tag| (k8s.deploy_container)(unique_tag))?;
+    std::fs::write(&deployed_tag_path, &deployed_tag)?;
+    remove_outdated_artifacts(&CACHE_PATH, function_name, source_hash)?;
+    Ok(deployed_tag)
 }
 
-impl Drop for K8s {
+impl Drop for Deployments {
+    /// delete the run's resources when the last clone of the platform is dropped
     #[tracing::instrument]
     fn drop(&mut self) {
         let status = Command::new("kubectl")
             .args(
                 format!(
                     "delete pods,deployments,services,ingress -l turbolift_run_id={}",
-                    self.run_id.to_string()
+                    self.run_id
                 )
                 .split(' '),
             )
@@ -429,3 +609,38 @@ impl Drop for K8s {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// checks the names of a function's resources against kubernetes' limits:
+    /// 63 characters for containers and services (DNS labels), and 253 for
+    /// deployments and ingresses (DNS subdomains).
+    fn assert_names_fit(app_name: &str) {
+        assert!(format!("{}-app", app_name).len() <= 63);
+        assert!(format!("{}-service", app_name).len() <= 63);
+        assert!(format!("{}-deployment", app_name).len() <= 253);
+        assert!(format!("{}-ingress", app_name).len() <= 253);
+    }
+
+    #[test]
+    fn shortens_app_names() {
+        let run_id = Uuid::nil();
+        assert_eq!(
+            app_name("scratch_add", &run_id),
+            format!("scratch-add-{}", run_id)
+        );
+        assert_names_fit(&app_name("scratch_add", &run_id));
+        let shortened = app_name("scratch_compute_kernels_triple_0a1b2c3d", &run_id);
+        assert!(shortened.starts_with("scratch-c-"));
+        assert_names_fit(&shortened);
+
+        // services have no hash of their own, and can share a start and an end
+        let image = app_name("my_big_application_image_processing", &run_id);
+        let text = app_name("my_big_application_text_processing", &run_id);
+        assert_ne!(image, text);
+        assert_names_fit(&image);
+        assert_names_fit(&app_name(&"f".repeat(200), &run_id));
+    }
+}
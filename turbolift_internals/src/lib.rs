@@ -2,19 +2,26 @@
 extern crate lazy_static;
 use std::path::Path;
 
+pub mod attributes;
 pub mod build_project;
+pub mod codec;
 pub mod distributed_platform;
 pub mod extract_function;
 pub mod kubernetes;
 pub mod local_queue;
+pub mod readiness;
+pub mod resources;
+pub mod retry;
 pub mod utils;
+pub mod worker;
 pub use serde_json;
 pub use uuid;
 
 lazy_static! {
     /// CACHE_PATH is the directory where turbolift stores derived projects,
     /// their dependencies, and their build artifacts. Each distributed
-    /// function has its own project subdirectory in CACHE_PATH.
+    /// function or service has its own project subdirectory in CACHE_PATH,
+    /// named by [`extract_function::derived_project_name`].
     pub static ref CACHE_PATH: &'static Path  = Path::new(".turbolift");
 }
 
@@ -1,126 +1,270 @@
 extern crate proc_macro;
 use std::collections::HashMap;
 use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
 use std::path::Path;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
 
 use async_trait::async_trait;
 use std::process::{Child, Command};
+use std::time::Duration;
 use tokio_compat_02::FutureExt;
 use url::Url;
 
-use crate::build_project::make_executable;
+use crate::build_project::{make_executable, remove_outdated_artifacts};
+use crate::codec::Codec;
 use crate::distributed_platform::{
-    ArgsString, DistributionPlatform, DistributionResult, JsonResponse,
+    with_timeout, DeclarationLocks, DispatchOptions, DistributionError, DistributionPlatform,
+    DistributionResult, Payload,
 };
 use crate::extract_function::decompress_proj_src;
+use crate::readiness::{health_probe_succeeds, poll_until_ready, ReadinessPolicy};
+use crate::resources::ResourceHints;
+use crate::retry::{with_retries, RetryPolicy};
 use crate::CACHE_PATH;
 use uuid::Uuid;
 
 type AddressAndPort = Url;
 type FunctionName = String;
 
-#[derive(Default, Debug)]
+/// `LocalQueue` runs each distributed function as a server on this machine.
+/// Clones share their servers, so callers can release the lock on the
+/// platform before declaring or dispatching.
+#[derive(Default, Debug, Clone)]
 pub struct LocalQueue {
-    fn_name_to_address: HashMap<FunctionName, AddressAndPort>, // todo hardcoded rn
-    fn_name_to_process: HashMap<FunctionName, Child>,
-    fn_name_to_binary_path: HashMap<FunctionName, std::path::PathBuf>,
+    servers: Arc<Servers>,
     request_client: reqwest::Client,
     run_id: Uuid,
+    readiness_policy: ReadinessPolicy,
+    codec: Codec,
+    request_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+}
+
+/// the servers started by a `LocalQueue` and its clones.
+#[derive(Default, Debug)]
+struct Servers {
+    declaring: DeclarationLocks,
+    addresses: RwLock<HashMap<FunctionName, AddressAndPort>>,
+    processes: Mutex<HashMap<FunctionName, Child>>,
 }
 
 impl LocalQueue {
     pub fn new() -> LocalQueue {
         Default::default()
     }
+
+    /// sets how long to wait for a newly spawned server to respond to its
+    /// health probe before the first dispatch to it fails.
+    pub fn with_readiness_policy(mut self, readiness_policy: ReadinessPolicy) -> LocalQueue {
+        self.readiness_policy = readiness_policy;
+        self
+    }
+
+    /// sets the codec for functions that don't choose one in their `on`
+    /// attribute.
+    pub fn with_codec(mut self, codec: Codec) -> LocalQueue {
+        self.codec = codec;
+        self
+    }
+
+    /// sets how long to wait for a response from a function that doesn't set
+    /// its own `timeout` in its `on` attribute. By default, there's no limit.
+    pub fn with_timeout(mut self, timeout: Duration) -> LocalQueue {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// sets how calls to functions marked `idempotent` are retried after a
+    /// transient failure.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> LocalQueue {
+        self.retry_policy = retry_policy;
+        self
+    }
 }
 
 #[async_trait]
 impl DistributionPlatform for LocalQueue {
-    /// declare a function. Runs once.
+    /// declare a function. Runs once: builds the function's server unless a
+    /// server was already built from the same source, starts it, and waits
+    /// for it to be ready.
     #[tracing::instrument(skip(project_tar))]
-    async fn declare(&mut self, function_name: &str, project_tar: &[u8]) -> DistributionResult<()> {
-        let relative_build_dir = Path::new(".")
-            .join(".turbolift")
-            .join(".worker_build_cache");
-        fs::create_dir_all(&relative_build_dir)?;
-        let build_dir = relative_build_dir.canonicalize()?;
-        decompress_proj_src(project_tar, &build_dir).unwrap();
-        let function_executable = Path::new(CACHE_PATH.as_os_str()).join(format!(
-            "{}_{}_server",
-            function_name.to_string(),
-            self.run_id.as_u128()
-        ));
-        make_executable(&build_dir.join(function_name), Some(&function_executable))?;
-        self.fn_name_to_binary_path
-            .insert(function_name.to_string(), function_executable);
-        //std::fs::remove_dir_all(build_dir.join(function_name)).unwrap(); todo
+    async fn declare(
+        &self,
+        function_name: &str,
+        project_tar: &[u8],
+        source_hash: &str,
+        resources: &ResourceHints,
+    ) -> DistributionResult<()> {
+        let _declaring = self.servers.declaring.lock(function_name).await;
+        // another call may have declared the function while we waited for the lock
+        if self.has_declared(function_name) {
+            return Ok(());
+        }
+        let function_executable = Path::new(CACHE_PATH.as_os_str())
+            .join(format!("{}_{}_server", function_name, source_hash));
+        if function_executable.exists() {
+            tracing::info!("reusing server built from the same source");
+        } else {
+            let relative_build_dir = Path::new(".")
+                .join(".turbolift")
+                .join(".worker_build_cache");
+            fs::create_dir_all(&relative_build_dir)?;
+            let build_dir = relative_build_dir.canonicalize()?;
+            decompress_proj_src(project_tar, &build_dir)
+                .map_err(|e| DistributionError::build(function_name, e))?;
+            make_executable(&build_dir.join(function_name), Some(&function_executable))
+                .map_err(|e| DistributionError::build(function_name, e))?;
+            remove_outdated_artifacts(&CACHE_PATH, function_name, source_hash)?;
+            //std::fs::remove_dir_all(build_dir.join(function_name)).unwrap(); todo
+        }
+
+        let server_address_and_port_str = unused_local_address()?.to_string();
+        let server_url: AddressAndPort =
+            Url::parse(&("http://".to_string() + &server_address_and_port_str))?;
+        tracing::info!("spawning");
+        let mut command = Command::new(&function_executable);
+        command
+            .arg(server_address_and_port_str)
+            .envs(resources.worker_env());
+        limit_resources(&mut command, resources);
+        let mut server_handle = command
+            .spawn()
+            .map_err(|e| DistributionError::declare(function_name, e))?;
+        tracing::info!("waiting for server to respond to health probe");
+        let health_probe_url = server_url.join("health-probe")?;
+        let request_client = &self.request_client;
+        let readiness = poll_until_ready(function_name, &self.readiness_policy, || {
+            let exit_status = server_handle.try_wait();
+            let health_probe_url = &health_probe_url;
+            async move {
+                if let Some(status) = exit_status? {
+                    return Err(DistributionError::declare(
+                        function_name,
+                        format!("server exited before becoming ready: {}", status),
+                    ));
+                }
+                Ok(health_probe_succeeds(request_client, health_probe_url).await)
+            }
+        })
+        .await;
+        if let Err(e) = readiness {
+            let _ = server_handle.kill();
+            return Err(e);
+        }
+        tracing::info!("server ready");
+        self.servers
+            .addresses
+            .write()
+            .unwrap()
+            .insert(function_name.to_string(), server_url);
+        self.servers
+            .processes
+            .lock()
+            .unwrap()
+            .insert(function_name.to_string(), server_handle);
         Ok(())
     }
 
     // dispatch params to a function. Runs each time the function is called.
-    #[tracing::instrument]
+    #[tracing::instrument(skip(params))]
     async fn dispatch(
-        &mut self,
+        &self,
         function_name: &str,
-        params: ArgsString,
-    ) -> DistributionResult<JsonResponse> {
-        let address_and_port = {
-            if self.fn_name_to_address.contains_key(function_name) {
-                // the server is already initialized.
-                self.fn_name_to_address
-                    .get(function_name)
-                    .unwrap()
-                    .to_owned()
-            } else {
-                // we must initialize the server before sending any requests!
-                let server_address_and_port_str = "127.0.0.1:8101";
-                let server_url: AddressAndPort =
-                    Url::parse(&("http://".to_string() + server_address_and_port_str))?;
-                let executable = self.fn_name_to_binary_path.get(function_name).unwrap();
-                tracing::info!("spawning");
-                let server_handle = Command::new(executable)
-                    .arg(&server_address_and_port_str)
-                    .spawn()?;
-                tracing::info!("delaying");
-                tokio::time::sleep(Duration::from_secs(60)).await;
-                tracing::info!("delay completed");
-                // ^ sleep to make sure the server is initialized before continuing
-                self.fn_name_to_address
-                    .insert(function_name.to_string(), server_url.clone());
-                self.fn_name_to_process
-                    .insert(function_name.to_string(), server_handle);
-                server_url
-            }
-        };
+        params: Payload,
+        options: &DispatchOptions,
+    ) -> DistributionResult<Payload> {
+        let address_and_port = self
+            .servers
+            .addresses
+            .read()
+            .unwrap()
+            .get(function_name)
+            .cloned()
+            .ok_or_else(|| {
+                DistributionError::platform(format!("{} has not been declared", function_name))
+            })?;
 
         // request from server
-        let prefixed_params = "./".to_string() + function_name + "/empty-uuid/" + &params;
-        let query_url = address_and_port.join(&prefixed_params)?;
+        let function_path = format!(
+            "./{}/{}/{}",
+            function_name,
+            self.run_id,
+            options.relative_route()
+        );
+        let query_url = address_and_port.join(&function_path)?;
 
         tracing::info!("sending dispatch request");
-        Ok(self
-            .request_client
-            .get(query_url)
-            .send()
-            .compat()
-            .await?
-            .text()
-            .compat()
-            .await?)
+        let timeout = options.timeout.or(self.request_timeout);
+        let retry_policy = options.retry_policy(&self.retry_policy);
+        with_retries(function_name, retry_policy.as_ref(), || {
+            with_timeout(
+                function_name,
+                timeout,
+                params
+                    .post(&self.request_client, query_url.clone())
+                    .compat(),
+            )
+        })
+        .await
     }
 
     #[tracing::instrument]
     fn has_declared(&self, fn_name: &str) -> bool {
-        self.fn_name_to_binary_path.contains_key(fn_name)
+        self.servers.addresses.read().unwrap().contains_key(fn_name)
+    }
+
+    fn default_codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+/// asks the OS for a free port on localhost, so that each function's server
+/// can run at the same time as the others. The port is released before the
+/// server binds it, so another process could claim it in between; in that
+/// case the server exits and the readiness check reports the failure.
+fn unused_local_address() -> std::io::Result<SocketAddr> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+/// applies the memory hint to the server process as a limit on its data
+/// segment. Each function runs as a single local process, so the cpu and
+/// replicas hints are ignored.
+fn limit_resources(command: &mut Command, resources: &ResourceHints) {
+    if resources.cpu.is_some() || resources.replicas.is_some() {
+        tracing::debug!("cpu and replicas hints are ignored by the local queue");
+    }
+    #[cfg(unix)]
+    if let Some(memory_bytes) = resources.memory_bytes() {
+        use std::os::unix::process::CommandExt;
+
+        let limit = libc::rlimit {
+            rlim_cur: memory_bytes as libc::rlim_t,
+            rlim_max: memory_bytes as libc::rlim_t,
+        };
+        // only calls setrlimit, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_DATA, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    if resources.memory.is_some() {
+        tracing::warn!("memory hints are only applied by the local queue on unix");
     }
 }
 
-impl Drop for LocalQueue {
-    /// terminate all servers when program is finished
+impl Drop for Servers {
+    /// terminate all servers when the last clone of the platform is dropped
     #[tracing::instrument]
     fn drop(&mut self) {
-        self.fn_name_to_process
+        self.processes
+            .get_mut()
+            .unwrap()
             .drain()
             .for_each(|(_filename, mut handle)| handle.kill().unwrap());
     }
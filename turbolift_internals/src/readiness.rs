@@ -0,0 +1,121 @@
+use std::future::Future;
+
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_compat_02::FutureExt;
+use url::Url;
+
+use crate::distributed_platform::{DistributionError, DistributionResult};
+
+/// How long a platform waits for a newly started worker to report that it is
+/// ready, and how often it checks in the meantime.
+#[derive(Debug, Clone)]
+pub struct ReadinessPolicy {
+    /// total time to wait before giving up on the worker.
+    pub deadline: Duration,
+    /// delay between the first and second check.
+    pub initial_backoff: Duration,
+    /// the delay between checks doubles after each failed check until it
+    /// reaches `max_backoff`.
+    pub max_backoff: Duration,
+}
+
+impl ReadinessPolicy {
+    pub fn new(deadline: Duration) -> ReadinessPolicy {
+        ReadinessPolicy {
+            deadline,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for ReadinessPolicy {
+    fn default() -> Self {
+        ReadinessPolicy {
+            deadline: Duration::from_secs(5 * 60),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// calls `is_ready` with exponential backoff until it returns true, failing
+/// with [`DistributionError::Timeout`] once the policy's deadline has passed,
+/// including while a check is still running. Errors returned by `is_ready`
+/// are not retried.
+#[tracing::instrument(skip(is_ready))]
+pub async fn poll_until_ready<F, Fut>(
+    function_name: &str,
+    policy: &ReadinessPolicy,
+    mut is_ready: F,
+) -> DistributionResult<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = DistributionResult<bool>>,
+{
+    let start = Instant::now();
+    let timed_out = || DistributionError::Timeout {
+        function_name: function_name.to_string(),
+        activity: "waiting for the worker to become ready",
+        waited: start.elapsed(),
+    };
+    let mut backoff = policy.initial_backoff;
+    loop {
+        let remaining = policy.deadline.saturating_sub(start.elapsed());
+        // a worker that accepts connections without answering must not stall the check
+        let ready = timeout(remaining, is_ready())
+            .await
+            .map_err(|_| timed_out())?;
+        if ready? {
+            tracing::info!(waited = ?start.elapsed(), "worker ready");
+            return Ok(());
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= policy.deadline {
+            return Err(timed_out());
+        }
+        sleep(backoff.min(policy.deadline - elapsed)).await;
+        backoff = (backoff * 2).min(policy.max_backoff);
+    }
+}
+
+/// polls the worker's health probe until it responds successfully.
+#[tracing::instrument(skip(client))]
+pub async fn wait_for_health_probe(
+    client: &reqwest::Client,
+    function_name: &str,
+    health_probe_url: &Url,
+    policy: &ReadinessPolicy,
+) -> DistributionResult<()> {
+    poll_until_ready(function_name, policy, || async move {
+        Ok(health_probe_succeeds(client, health_probe_url).await)
+    })
+    .await
+}
+
+/// sends a single request to a worker's health probe. Connection errors are
+/// expected while the worker is starting, so they are treated as "not ready
+/// yet" instead of as failures.
+pub async fn health_probe_succeeds(client: &reqwest::Client, health_probe_url: &Url) -> bool {
+    match client.get(health_probe_url.clone()).send().compat().await {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            tracing::debug!(error = %e, "health probe not reachable yet");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn times_out_checks_that_never_finish() {
+        let policy = ReadinessPolicy::new(Duration::from_millis(50));
+        let result = poll_until_ready("f", &policy, || {
+            futures::future::pending::<DistributionResult<bool>>()
+        })
+        .await;
+        assert!(matches!(result, Err(DistributionError::Timeout { .. })));
+    }
+}
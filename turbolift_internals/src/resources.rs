@@ -0,0 +1,120 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+
+use crate::worker;
+
+/// Resource requirements attached to a distributed function with the `with`
+/// attribute, e.g. `#[with(cpu = "500m", memory = "256Mi", replicas = 2)]`.
+/// Platforms apply the hints they support when the function is declared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceHints {
+    /// CPU in Kubernetes notation, e.g. `"2"`, `"0.5"`, or `"500m"`.
+    pub cpu: Option<String>,
+    /// memory in Kubernetes notation, e.g. `"256Mi"`, `"1G"`, or `"1048576"`.
+    pub memory: Option<String>,
+    /// number of workers to start for the function.
+    pub replicas: Option<u32>,
+    /// number of calls each worker runs at once.
+    pub concurrency: Option<u32>,
+    /// environment variables set for the function's workers.
+    pub env: Vec<(String, String)>,
+}
+
+impl ResourceHints {
+    /// the memory hint in bytes, if it is set and valid.
+    pub fn memory_bytes(&self) -> Option<u64> {
+        self.memory.as_deref().and_then(parse_memory)
+    }
+
+    /// the environment variables to set for the function's workers, including
+    /// the ones that pass hints to the generated server.
+    pub fn worker_env(&self) -> Vec<(String, String)> {
+        let mut env = self.env.clone();
+        if let Some(concurrency) = self.concurrency {
+            env.push((
+                worker::CONCURRENCY_ENV_VAR.to_string(),
+                concurrency.to_string(),
+            ));
+        }
+        env
+    }
+}
+
+/// parses a Kubernetes memory quantity like `"256Mi"` or `"1G"` into bytes.
+pub fn parse_memory(quantity: &str) -> Option<u64> {
+    const SUFFIXES: [(&str, u64); 8] = [
+        ("Ki", 1 << 10),
+        ("Mi", 1 << 20),
+        ("Gi", 1 << 30),
+        ("Ti", 1 << 40),
+        ("k", 1_000),
+        ("M", 1_000_000),
+        ("G", 1_000_000_000),
+        ("T", 1_000_000_000_000),
+    ];
+    let (number, multiplier) = SUFFIXES
+        .iter()
+        .find_map(|(suffix, multiplier)| {
+            quantity
+                .strip_suffix(suffix)
+                .map(|number| (number, *multiplier))
+        })
+        .unwrap_or((quantity, 1));
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// checks that a CPU quantity is in Kubernetes notation: a positive number of
+/// cores like `"2"` or `"0.5"`, or of millicores like `"500m"`.
+pub fn is_valid_cpu(quantity: &str) -> bool {
+    match quantity.strip_suffix('m') {
+        Some(millicores) => millicores.parse::<u64>().is_ok_and(|m| m > 0),
+        None => quantity.parse::<f64>().is_ok_and(|cores| cores > 0.0),
+    }
+}
+
+impl ToTokens for ResourceHints {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        let option_string = |value: &Option<String>| match value {
+            Some(value) => quote! { Some(#value.to_string()) },
+            None => quote! { None },
+        };
+        let cpu = option_string(&self.cpu);
+        let memory = option_string(&self.memory);
+        let option_u32 = |value: Option<u32>| match value {
+            Some(value) => quote! { Some(#value) },
+            None => quote! { None },
+        };
+        let replicas = option_u32(self.replicas);
+        let concurrency = option_u32(self.concurrency);
+        let env = self
+            .env
+            .iter()
+            .map(|(key, value)| quote! { (#key.to_string(), #value.to_string()) });
+        tokens.extend(quote! {
+            turbolift::resources::ResourceHints {
+                cpu: #cpu,
+                memory: #memory,
+                replicas: #replicas,
+                concurrency: #concurrency,
+                env: vec![#(#env),*],
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quantities() {
+        assert_eq!(parse_memory("256Mi"), Some(256 << 20));
+        assert_eq!(parse_memory("1G"), Some(1_000_000_000));
+        assert_eq!(parse_memory("4096"), Some(4096));
+        assert_eq!(parse_memory("lots"), None);
+        assert!(is_valid_cpu("500m"));
+        assert!(is_valid_cpu("0.5"));
+        assert!(!is_valid_cpu("0"));
+        assert!(!is_valid_cpu("fast"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+
+use tokio::time::{sleep, Duration};
+
+use crate::distributed_platform::{DistributionError, DistributionResult};
+
+/// How a platform retries calls that fail with a transient error. Only
+/// functions marked `idempotent` in their `on` attribute are retried, because
+/// a failed request may still have run the function.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// delay between the first and second attempt.
+    pub initial_backoff: Duration,
+    /// the delay between attempts doubles after each failure until it
+    /// reaches `max_backoff`.
+    pub max_backoff: Duration,
+    /// decides which errors are worth another attempt.
+    pub is_retryable: fn(&DistributionError) -> bool,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            is_retryable: DistributionError::is_transient,
+        }
+    }
+}
+
+/// runs `attempt` until it succeeds, fails with an error the policy doesn't
+/// retry, or runs out of attempts. Without a policy, `attempt` runs once.
+#[tracing::instrument(skip(attempt))]
+pub async fn with_retries<F, Fut, T>(
+    function_name: &str,
+    policy: Option<&RetryPolicy>,
+    mut attempt: F,
+) -> DistributionResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = DistributionResult<T>>,
+{
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return attempt().await,
+    };
+    let mut backoff = policy.initial_backoff;
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(e) if attempts < policy.max_attempts && (policy.is_retryable)(&e) => {
+                tracing::warn!(error = %e, attempts, "transient failure, retrying");
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration as StdDuration;
+
+    fn timeout() -> DistributionError {
+        DistributionError::Timeout {
+            function_name: "f".to_string(),
+            activity: "waiting for a response",
+            waited: StdDuration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_until_out_of_attempts() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::new(3)
+        };
+        let mut calls = 0;
+        let result: DistributionResult<()> = with_retries("f", Some(&policy), || {
+            calls += 1;
+            async { Err(timeout()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: DistributionResult<()> = with_retries("f", None, || {
+            calls += 1;
+            async { Err(timeout()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
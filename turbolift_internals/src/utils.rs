@@ -26,3 +26,42 @@ pub const DEBUG_FLAG: &str = {
         " --debug"
     }
 };
+
+/// hashes a sequence of byte strings into a hex digest. Unlike `std`'s
+/// hashers, the digest stays the same across toolchains, so it can name things
+/// that outlive a build. Each part is prefixed with its length, so that moving
+/// bytes between parts changes the digest.
+#[derive(Default)]
+pub struct StableHasher(sha1::Sha1);
+
+impl StableHasher {
+    pub fn new() -> StableHasher {
+        Default::default()
+    }
+
+    pub fn write(&mut self, part: &[u8]) {
+        use sha1::Digest;
+
+        self.0.update((part.len() as u64).to_le_bytes());
+        self.0.update(part);
+    }
+
+    pub fn finish(self) -> String {
+        use sha1::Digest;
+
+        self.0
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// the [`StableHasher`] digest of the parts.
+pub fn stable_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = StableHasher::new();
+    for part in parts {
+        hasher.write(part);
+    }
+    hasher.finish()
+}
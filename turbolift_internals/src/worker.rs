@@ -0,0 +1,29 @@
+/// Sets the number of calls a worker runs at once. Platforms set it from the
+/// `concurrency` hint of the `with` attribute.
+pub const CONCURRENCY_ENV_VAR: &str = "TURBOLIFT_WORKER_CONCURRENCY";
+
+/// the size of the thread pool that actix-web uses for `web::block`.
+const ACTIX_THREADPOOL_ENV_VAR: &str = "ACTIX_THREADPOOL";
+
+/// the number of calls to a synchronous function that a worker runs at once,
+/// from [`CONCURRENCY_ENV_VAR`] or else the number of available cores.
+pub fn concurrency() -> usize {
+    std::env::var(CONCURRENCY_ENV_VAR)
+        .ok()
+        .and_then(|concurrency| concurrency.parse::<usize>().ok())
+        .filter(|concurrency| *concurrency > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|cores| cores.get())
+                .unwrap_or(1)
+        })
+}
+
+/// sizes the thread pool that synchronous functions run on, so that they
+/// don't block the event loop answering requests and health probes. Must be
+/// called before the server starts. Returns the size of the pool.
+pub fn configure_blocking_pool() -> usize {
+    let concurrency = concurrency();
+    std::env::set_var(ACTIX_THREADPOOL_ENV_VAR, concurrency.to_string());
+    concurrency
+}
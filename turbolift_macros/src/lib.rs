@@ -1,108 +1,552 @@
 use proc_macro::TokenStream;
-use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use proc_macro2::TokenStream as TokenStream2;
 use quote::quote as q;
 
+use turbolift_internals::attributes::{self, OnArgs};
 use turbolift_internals::extract_function;
+#[cfg(feature = "distributed")]
+use turbolift_internals::resources::ResourceHints;
+
+/// the name of the path parameter that holds the caller's run id in the
+/// worker's routes.
+#[cfg(feature = "distributed")]
+const RUN_ID_NAME: &str = "_turbolift_run_id";
 
 #[cfg(feature = "distributed")]
 #[proc_macro_attribute]
 #[tracing::instrument]
 pub fn on(distribution_platform_: TokenStream, function_: TokenStream) -> TokenStream {
     use quote::{format_ident, ToTokens};
-    use std::fs;
-    use std::path::PathBuf;
-    use std::str::FromStr;
-
-    use turbolift_internals::{build_project, CACHE_PATH};
-
-    const RUN_ID_NAME: &str = "_turbolift_run_id";
 
     // convert proc_macro::TokenStream to proc_macro2::TokenStream
-    let distribution_platform = TokenStream2::from(distribution_platform_);
+    let mut args = match syn::parse::<OnArgs>(distribution_platform_) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
     let function = TokenStream2::from(function_);
 
     // generate derived syntax
-    let original_target_function = extract_function::get_fn_item(function.clone());
+    let mut original_target_function = match extract_function::get_fn_item(function.clone()) {
+        Ok(function) => function,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let resources =
+        match attributes::take_with_attributes(&mut args, &mut original_target_function.attrs) {
+            Ok(resources) => resources,
+            Err(e) => return e.to_compile_error().into(),
+        };
+    let client_attributes = attributes::take_client_attributes(&mut original_target_function.attrs);
+    let receiver = match extract_function::get_receiver(&original_target_function.sig) {
+        Ok(receiver) => receiver,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if let Err(e) = extract_function::check_signature(&original_target_function.sig) {
+        return e.to_compile_error().into();
+    }
+    // the package's sources are only scanned once, for all of the lookups below
+    let sources = match extract_function::SourceFiles::scan() {
+        Ok(sources) => sources,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // methods and associated functions are wrapped in an impl block on the worker
+    let impl_type = match extract_function::get_impl_type(&sources, &original_target_function) {
+        Ok(impl_type) => impl_type,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let instantiations =
+        match extract_function::get_instantiations(&original_target_function, &args.instantiate) {
+            Ok(instantiations) => instantiations,
+            Err(e) => return e.to_compile_error().into(),
+        };
+    if impl_type.is_some() && !instantiations.is_empty() {
+        return syn::Error::new_spanned(
+            &original_target_function.sig.generics,
+            "generic methods and associated functions can't be distributed",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let distribution_platform = args.distribution_platform;
     let original_target_function_ident = original_target_function.sig.ident.clone();
     let original_target_function_name = original_target_function_ident.to_string();
     let mut target_function = original_target_function.clone();
     target_function.sig.ident = format_ident!("{}_raw", target_function.sig.ident);
     let signature = target_function.sig.clone();
-    let function_name = signature.ident;
-    let function_name_string = function_name.to_string();
+    let serialization_checks = extract_function::make_serialization_checks(&signature);
     let typed_params = signature.inputs;
+    let generics = signature.generics;
     let untyped_params = extract_function::to_untyped_params(typed_params.clone());
-    let mut untyped_params_with_run_id = untyped_params.clone();
-    // we need to prepend a variable for the run id, since it's in the URL.
-    untyped_params_with_run_id.insert(
-        0,
-        Box::new(syn::Pat::Ident(syn::PatIdent {
-            attrs: Vec::new(),
-            by_ref: None,
-            mutability: None,
-            ident: Ident::new(RUN_ID_NAME, Span::call_site()),
-            subpat: None,
-        })),
-    );
-    let untyped_params_tokens_with_run_id = untyped_params_with_run_id.to_token_stream();
-    let untyped_params_tokens = untyped_params.to_token_stream();
-    let params_as_path = extract_function::to_path_params(untyped_params.clone());
-    let wrapper_route = format!(
-        "{}/{{{}}}/{}",
-        original_target_function_name, RUN_ID_NAME, &params_as_path
-    );
-    let mut param_types = extract_function::to_param_types(typed_params.clone());
-    // we need to prepend a type for the run id added to the wrapper route
-    param_types.insert(
-        0,
-        Box::new(syn::Type::Verbatim(
-            str::parse::<TokenStream2>("String")
-                .expect("could not parse \"String\" as a tokenstream"),
-        )),
+    let borrowed_params_tuple =
+        extract_function::to_borrowed_params_tuple(receiver.as_ref(), untyped_params.clone());
+    let result_type = extract_function::get_result_type(&signature.output);
+
+    let project_name = match extract_function::derived_project_name(
+        &sources,
+        &original_target_function,
+        args.service.as_deref(),
+    ) {
+        Ok(project_name) => project_name,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // the functions of a service share a worker, so each of them builds the
+    // worker with all of the service's functions
+    let worker_functions = match &args.service {
+        None => vec![(original_target_function.clone(), args.instantiate.clone())],
+        Some(service) => {
+            let functions = match extract_function::find_service_functions(
+                &sources,
+                &original_target_function,
+                service,
+            )
+            .and_then(|functions| {
+                functions
+                    .into_iter()
+                    .map(prepare_service_function)
+                    .collect::<syn::Result<Vec<_>>>()
+            }) {
+                Ok(functions) => functions,
+                Err(e) => return e.to_compile_error().into(),
+            };
+            let same_name_count = functions
+                .iter()
+                .filter(|(function, _, _)| function.sig.ident == original_target_function_ident)
+                .count();
+            if same_name_count > 1 {
+                return syn::Error::new_spanned(
+                    &original_target_function_ident,
+                    format!(
+                        "the service `{}` has more than one function called `{}`, rename one of them",
+                        service, original_target_function_name
+                    ),
+                )
+                .to_compile_error()
+                .into();
+            }
+            // the service is declared with the hints of whichever of its
+            // functions is called first, so they have to agree
+            let normalized = |hints: &ResourceHints| {
+                let mut hints = hints.clone();
+                hints.env.sort();
+                hints
+            };
+            let differing_function = functions
+                .iter()
+                .find(|(_, _, hints)| normalized(hints) != normalized(&resources));
+            if let Some((function, _, _)) = differing_function {
+                return syn::Error::new_spanned(
+                    &original_target_function_ident,
+                    format!(
+                        "the functions of the service `{}` share a worker, so they need the same `with` hints, but `{}` and `{}` have different ones",
+                        service, original_target_function_name, function.sig.ident
+                    ),
+                )
+                .to_compile_error()
+                .into();
+            }
+            functions
+                .into_iter()
+                .map(|(function, instantiate, _)| (function, instantiate))
+                .collect()
+        }
+    };
+    let mut module_glues = Vec::new();
+    let mut configure_paths = Vec::new();
+    for (function, instantiate) in worker_functions {
+        match make_module_glue(
+            &sources,
+            &function,
+            &instantiate,
+            &project_name,
+            args.service.is_some(),
+        ) {
+            Ok((module_glue, configure_path)) => {
+                module_glues.push((function, module_glue));
+                configure_paths.push(configure_path);
+            }
+            Err(e) => return e.to_compile_error().into(),
+        }
+    }
+    let root_glue = make_root_glue(&project_name, &configure_paths);
+
+    // edit the functions' modules and the crate root
+    let worker_sources =
+        match extract_function::make_worker_sources(&sources, &module_glues, root_glue) {
+            Ok(worker_sources) => worker_sources,
+            Err(e) => return e.to_compile_error().into(),
+        };
+    let (project_source_binary, source_hash) =
+        match make_worker_project(&project_name, worker_sources) {
+            Ok((tar_file, source_hash)) => (q! { std::include_bytes!(#tar_file) }, source_hash),
+            Err(e) => {
+                return syn::Error::new_spanned(
+                    &original_target_function_ident,
+                    format!("could not prepare the worker's project: {}", e),
+                )
+                .to_compile_error()
+                .into()
+            }
+        };
+
+    let timeout = attributes::duration_tokens(args.timeout);
+    let idempotent = args.idempotent;
+    let max_retries = match args.retries {
+        Some(retries) => q! { Some(#retries) },
+        None => q! { None },
+    };
+    let codec = match args.codec {
+        Some(codec) => codec.into_token_stream(),
+        None => q! { platform.default_codec() },
+    };
+
+    let dispatched_function = match &args.service {
+        Some(_) => q! { Some(#original_target_function_name.to_string()) },
+        None => q! { None },
+    };
+
+    let instrument = instrument_attribute(receiver.is_some(), !instantiations.is_empty());
+    let vis = &original_target_function.vis;
+    let with_markers = target_function
+        .attrs
+        .iter()
+        .filter(|attr| attributes::is_with_attribute(attr));
+    let typed_params = extract_function::without_mutability(typed_params);
+    let client_generics = extract_function::with_distribution_bounds(
+        &original_target_function_ident,
+        &generics,
+        &typed_params,
+        &result_type,
     );
-    let params_vec = extract_function::params_json_vec(untyped_params.clone());
+    let client_where_clause = &client_generics.where_clause;
+    let (instantiation_trait, instantiation) = if instantiations.is_empty() {
+        (TokenStream2::new(), q! { None })
+    } else {
+        let trait_ident =
+            extract_function::instantiation_trait_ident(&original_target_function_ident);
+        let type_params = generics.type_params().map(|param| &param.ident);
+        (
+            extract_function::make_instantiation_trait(
+                &original_target_function_ident,
+                &original_target_function.vis,
+                &instantiations,
+            ),
+            q! { Some(<(#(#type_params,)*) as #trait_ident>::INSTANTIATION.to_string()) },
+        )
+    };
+
+    // generate API function for the microservice. This may be in an impl
+    // block, so it has to be a single associated function unless it's generic.
+    let declare_and_dispatch = q! {
+        #instantiation_trait
+
+        // dispatch call and process response
+        #(#client_attributes)*
+        #(#with_markers)*
+        #instrument
+        #vis async fn #original_target_function_ident #client_generics(#typed_params) ->
+            turbolift::DistributionResult<#result_type> #client_where_clause {
+            #serialization_checks
+            use std::time::Duration;
+            use turbolift::distributed_platform::DistributionPlatform;
+            use turbolift::DistributionResult;
+            use turbolift::tokio_compat_02::FutureExt;
+            use turbolift::uuid::Uuid;
+
+            // the platform is a handle to shared state, so the lock is only held to clone it
+            let platform = #distribution_platform.lock().await.clone();
+            if !platform.has_declared(#project_name) {
+                platform
+                    .declare(
+                        #project_name,
+                        #project_source_binary,
+                        #source_hash,
+                        &#resources,
+                    )
+                    .compat()
+                    .await?;
+            }
+
+            let codec = #codec;
+            let params = turbolift::distributed_platform::Payload::new(
+                codec,
+                codec.encode(&#borrowed_params_tuple)?
+            );
+            let options = turbolift::distributed_platform::DispatchOptions {
+                timeout: #timeout,
+                idempotent: #idempotent,
+                max_retries: #max_retries,
+                instantiation: #instantiation,
+                function: #dispatched_function,
+            };
+            let response = platform
+                .dispatch(
+                    #project_name,
+                    params,
+                    &options
+                )
+                .compat()
+                .await?;
+            response.decode()
+        }
+    };
+    declare_and_dispatch.into()
+}
+
+/// the worker's side of a distributed function: the function itself, a dummy
+/// for calls to it from the rest of the worker's code, and the request
+/// handler. Returns the glue for the function's module and the path of the
+/// function that adds its routes to the worker's server.
+#[cfg(feature = "distributed")]
+fn make_module_glue(
+    sources: &extract_function::SourceFiles,
+    original_target_function: &syn::ItemFn,
+    instantiate: &[syn::Type],
+    project_name: &str,
+    in_service: bool,
+) -> syn::Result<(TokenStream2, TokenStream2)> {
+    use quote::format_ident;
+
+    let receiver = extract_function::get_receiver(&original_target_function.sig)?;
+    let impl_type = extract_function::get_impl_type(sources, original_target_function)?;
+    let instantiations =
+        extract_function::get_instantiations(original_target_function, instantiate)?;
+    let original_target_function_name = original_target_function.sig.ident.to_string();
+    let mut target_function = original_target_function.clone();
+    target_function.sig.ident = format_ident!("{}_raw", target_function.sig.ident);
+    let signature = target_function.sig.clone();
+    let function_name = signature.ident;
+    let typed_params = signature.inputs;
+    let generics = signature.generics;
+    let turbofish = extract_function::to_turbofish(&generics);
+    let untyped_params = extract_function::to_untyped_params(typed_params.clone());
+    let run_id_ident = format_ident!("{}", RUN_ID_NAME);
+    // the functions of a service have a route under the service's project
+    let wrapper_ident = format_ident!("turbolift_wrapper_{}", original_target_function_name);
+    let configure_ident = format_ident!("turbolift_configure_{}", original_target_function_name);
+    let function_route = if in_service {
+        format!(
+            "{}/{{{}}}/{}/",
+            project_name, RUN_ID_NAME, original_target_function_name
+        )
+    } else {
+        format!("{}/{{{}}}/", project_name, RUN_ID_NAME)
+    };
+    // generic functions have a route for each instantiation
+    let wrapper_routes = if instantiations.is_empty() {
+        q! {
+            .route(#function_route, turbolift::actix_web::web::post().to(#wrapper_ident))
+        }
+    } else {
+        let routes = instantiations.iter().map(|instantiation| {
+            let wrapper_route = format!("{}{}/", function_route, instantiation.name);
+            let types = &instantiation.types;
+            q! {
+                .route(
+                    #wrapper_route,
+                    turbolift::actix_web::web::post().to(#wrapper_ident::<#(#types),*>)
+                )
+            }
+        });
+        q! { #(#routes)* }
+    };
+
+    // the worker receives owned values, and lends them to reference parameters
+    let worker_args = extract_function::to_worker_args(typed_params.clone());
+    // the worker receives a method's receiver as the first parameter
+    let mut worker_params = untyped_params.clone();
+    let mut worker_param_types = extract_function::to_param_types(typed_params.clone());
+    let worker_call = match (&impl_type, &receiver) {
+        (Some(impl_type), Some(receiver)) => {
+            let receiver_ident = format_ident!("_turbolift_receiver");
+            worker_params.insert(0, Box::new(syn::parse_quote!(#receiver_ident)));
+            worker_param_types.insert(0, Box::new(impl_type.clone()));
+            let receiver_arg = match receiver.reference {
+                Some(_) => q! { &#receiver_ident },
+                None => q! { #receiver_ident },
+            };
+            q! { <#impl_type>::#function_name(#receiver_arg, #(#worker_args),*) }
+        }
+        (Some(impl_type), None) => q! { <#impl_type>::#function_name(#(#worker_args),*) },
+        (None, _) => q! { #function_name #turbofish(#(#worker_args),*) },
+    };
+    // iterators are collected where they're made, since they can't be sent
+    let worker_call = match (
+        extract_function::iterator_item_type(&signature.output),
+        signature.asyncness,
+    ) {
+        (None, _) => worker_call,
+        (Some(_), None) => q! { Iterator::collect::<Vec<_>>(#worker_call) },
+        (Some(_), Some(_)) => q! {
+            turbolift::futures::FutureExt::map(#worker_call, Iterator::collect::<Vec<_>>)
+        },
+    };
+    // panics are reported to the caller instead of dropping the connection.
+    // synchronous functions run on the blocking pool so that they don't hold
+    // up the event loop, which also answers the health probe.
+    let caught_worker_call = match signature.asyncness {
+        Some(_) => q! {
+            turbolift::futures::FutureExt::catch_unwind(
+                std::panic::AssertUnwindSafe(#worker_call)
+            ).await
+        },
+        None => q! {
+            turbolift::actix_web::web::block(move || {
+                Ok::<_, ()>(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| #worker_call)))
+            })
+            .await
+            .unwrap_or_else(|e| Err(Box::new(format!("could not run on the blocking pool: {}", e))))
+        },
+    };
+    let params_tuple = extract_function::to_params_tuple(worker_params);
+    let mut param_types_tuple = extract_function::to_param_types_tuple(worker_param_types);
+    if let Some(impl_type) = &impl_type {
+        param_types_tuple = extract_function::replace_self_type(param_types_tuple, impl_type);
+    }
+
     let result_type = extract_function::get_result_type(&signature.output);
+    let mut worker_generics = generics.clone();
+    if !instantiations.is_empty() {
+        let predicates = &mut worker_generics.make_where_clause().predicates;
+        predicates.push(syn::parse_quote! {
+            #param_types_tuple: turbolift::serde::de::DeserializeOwned
+        });
+        predicates.push(syn::parse_quote! { #result_type: turbolift::serde::Serialize });
+        if signature.asyncness.is_none() {
+            // moved to and from the blocking pool
+            predicates.push(syn::parse_quote! { #param_types_tuple: Send + 'static });
+            predicates.push(syn::parse_quote! { #result_type: Send + 'static });
+        }
+    }
+    let worker_where_clause = &worker_generics.where_clause;
     let dummy_function = extract_function::make_dummy_function(
-        original_target_function,
-        &function_name_string,
-        untyped_params,
+        original_target_function.clone(),
+        extract_function::make_redirect_call(
+            &function_name,
+            &target_function.sig,
+            impl_type.is_some(),
+            receiver.as_ref(),
+            &untyped_params,
+        ),
     );
+    let worker_functions = match &impl_type {
+        Some(impl_type) => q! {
+            impl #impl_type {
+                #dummy_function
+                #target_function
+            }
+        },
+        None => q! {
+            #dummy_function
+            #target_function
+        },
+    };
 
-    // todo extract any docs from passed function and put into fn wrapper
+    // the wrapper is declared next to the function, so that the function's
+    // types resolve in it, and the server in the crate root refers to it.
+    // todo make code below hygienic in case the source files also import from actix_web
+    let module_glue = q! {
+        #worker_functions
 
-    // read current file to access imports and local functions
-    let sanitized_file = extract_function::get_sanitized_file(&function);
-    // todo make code below hygienic in case sanitized_file also imports from actix_web
-    let main_file = q! {
-        #sanitized_file
-        use turbolift::tokio_compat_02::FutureExt;
+        #[turbolift::tracing::instrument(skip(req, body))]
+        pub(crate) async fn #wrapper_ident #worker_generics(
+            turbolift::actix_web::web::Path(#run_id_ident): turbolift::actix_web::web::Path<String>,
+            req: turbolift::actix_web::HttpRequest,
+            body: turbolift::actix_web::web::Bytes,
+        ) -> impl turbolift::actix_web::Responder #worker_where_clause {
+            use turbolift::distributed_platform::{RemoteError, RemoteErrorKind};
 
-        #dummy_function
-        #target_function
+            let remote_error = |kind: RemoteErrorKind, message: String| {
+                RemoteError::new(kind, #original_target_function_name, &#run_id_ident, message)
+            };
 
-        async fn health_probe(_req: turbolift::actix_web::HttpRequest) -> impl turbolift::actix_web::Responder {
-            turbolift::actix_web::HttpResponse::Ok()
+            // answer with the codec that the dispatcher used for the parameters
+            let codec = match req.headers().get(turbolift::actix_web::http::header::CONTENT_TYPE) {
+                None => turbolift::codec::Codec::default(),
+                Some(content_type) => match content_type
+                    .to_str()
+                    .ok()
+                    .and_then(turbolift::codec::Codec::from_content_type)
+                {
+                    Some(codec) => codec,
+                    None => {
+                        return turbolift::actix_web::HttpResponse::UnsupportedMediaType().json(
+                            remote_error(
+                                RemoteErrorKind::UnsupportedMediaType,
+                                format!("unsupported content type: {:?}", content_type),
+                            ),
+                        )
+                    }
+                },
+            };
+            let #params_tuple: #param_types_tuple = match codec.decode(&body) {
+                Ok(params) => params,
+                Err(e) => {
+                    return turbolift::actix_web::HttpResponse::BadRequest().json(remote_error(
+                        RemoteErrorKind::InvalidArguments,
+                        format!("could not deserialize parameters: {}", e),
+                    ))
+                }
+            };
+            let result = match #caught_worker_call {
+                Ok(result) => result,
+                Err(panic) => {
+                    return turbolift::actix_web::HttpResponse::InternalServerError().json(
+                        RemoteError::from_panic(#original_target_function_name, &#run_id_ident, panic),
+                    )
+                }
+            };
+            match codec.encode(&result) {
+                Ok(result) => turbolift::actix_web::HttpResponse::Ok()
+                    .content_type(codec.content_type())
+                    .body(result),
+                Err(e) => turbolift::actix_web::HttpResponse::InternalServerError().json(
+                    remote_error(
+                        RemoteErrorKind::InvalidResult,
+                        format!("could not serialize result: {}", e),
+                    ),
+                ),
+            }
         }
 
-        #[turbolift::tracing::instrument]
-        async fn turbolift_wrapper(turbolift::actix_web::web::Path((#untyped_params_tokens_with_run_id)): turbolift::actix_web::web::Path<(#param_types)>) -> impl turbolift::actix_web::Responder {
+        pub(crate) fn #configure_ident(config: &mut turbolift::actix_web::web::ServiceConfig) {
+            config #wrapper_routes;
+        }
+    };
+    let module_path = extract_function::get_module_path(sources, original_target_function)?;
+    Ok((
+        module_glue,
+        q! { crate #(::#module_path)*::#configure_ident },
+    ))
+}
+
+/// the worker's `main`, which serves the functions of a service, or a single
+/// function, with the routes from their module glue.
+#[cfg(feature = "distributed")]
+fn make_root_glue(project_name: &str, configure_paths: &[TokenStream2]) -> TokenStream2 {
+    // lets platforms reach the health probe through the same path prefix as the functions
+    let prefixed_health_probe_route = format!("{}/{{{}}}/health-probe", project_name, RUN_ID_NAME);
+    q! {
+        use turbolift::tokio_compat_02::FutureExt;
+
+        async fn health_probe(_req: turbolift::actix_web::HttpRequest) -> impl turbolift::actix_web::Responder {
             turbolift::actix_web::HttpResponse::Ok()
-                .json(#function_name(#untyped_params_tokens))
         }
 
         #[turbolift::tracing::instrument]
         fn main() {
+            let concurrency = turbolift::worker::configure_blocking_pool();
             turbolift::actix_web::rt::System::new("main".to_string())
                 .block_on(async move {
                     let args: Vec<String> = std::env::args().collect();
                     let ip_and_port = &args[1];
                     turbolift::tracing::info!("service main() started. ip_and_port parsed.");
+                    turbolift::tracing::info!(concurrency, "blocking pool configured.");
                     turbolift::actix_web::HttpServer::new(
                         ||
                             turbolift::actix_web::App::new()
                                 .route(
-                                    #wrapper_route, turbolift::actix_web::web::get().to(turbolift_wrapper)
+                                    #prefixed_health_probe_route, turbolift::actix_web::web::get().to(health_probe)
                                 )
+                                #(.configure(#configure_paths))*
                                 .route(
                                     "/health-probe", turbolift::actix_web::web::get().to(health_probe)
                                 )
@@ -122,47 +566,109 @@ pub fn on(distribution_platform_: TokenStream, function_: TokenStream) -> TokenS
                     .await
                 }).unwrap();
     }
-    };
+    }
+}
 
-    // copy all files in repo into cache
-    let function_cache_proj_path = CACHE_PATH.join(original_target_function_name.clone());
-    fs::create_dir_all(function_cache_proj_path.clone()).unwrap();
-    let files_to_copy: Vec<PathBuf> = fs::read_dir(".")
-        .expect("could not read dir")
-        .map(|res| res.expect("could not read entry").path())
-        .filter(|path| path.file_name() != CACHE_PATH.file_name())
-        .filter(
-            |path| path.to_str() != Some("./target"),
-            // todo we could shorten compile time by sharing deps in ./target,
-            // but I didn't have the bandwidth to debug permissions errors caused
-            // by copying all of the compiled lib files.
-        )
+/// a function of a service as its worker sees it, without its `on`, `with`,
+/// and client attributes, along with its `instantiate` list and the hints
+/// from its `with` attributes.
+#[cfg(feature = "distributed")]
+fn prepare_service_function(
+    mut function: syn::ItemFn,
+) -> syn::Result<(syn::ItemFn, Vec<syn::Type>, ResourceHints)> {
+    let on_position = function
+        .attrs
+        .iter()
+        .position(attributes::is_on_attribute)
+        .ok_or_else(|| syn::Error::new_spanned(&function.sig, "expected an `on` attribute"))?;
+    let mut args = function.attrs.remove(on_position).parse_args::<OnArgs>()?;
+    let resources = attributes::take_with_attributes(&mut args, &mut function.attrs)?;
+    attributes::take_client_attributes(&mut function.attrs);
+    Ok((function, args.instantiate, resources))
+}
+
+/// archives the worker's project for the platform, unless its source hasn't
+/// changed since it was last archived. Returns the path of the archive and
+/// the hash of its source, which names the archive.
+#[cfg(feature = "distributed")]
+fn make_worker_project(
+    project_name: &str,
+    worker_sources: Vec<(std::path::PathBuf, TokenStream2)>,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    use std::path::PathBuf;
+
+    use turbolift_internals::{build_project, CACHE_PATH};
+
+    let worker_sources: Vec<(PathBuf, String)> = worker_sources
+        .into_iter()
+        .map(|(path, source)| (path, source.to_string()))
         .collect();
+    let source_hash = build_project::source_hash(
+        &extract_function::project_dir(),
+        project_name,
+        &worker_sources,
+    )?;
+    let tar_file = CACHE_PATH.join(format!("{}_{}_source.tar", project_name, source_hash));
+    if !tar_file.exists() {
+        make_compressed_worker_project(project_name, worker_sources, &tar_file)?;
+
+        build_project::remove_outdated_artifacts(&CACHE_PATH, project_name, &source_hash)?;
+    }
+    let tar_file = tar_file
+        .canonicalize()?
+        .to_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("{:?} is not valid unicode", tar_file))?;
+    Ok((tar_file, source_hash))
+}
+
+/// copies the project into the cache, replaces the source files in
+/// `worker_sources`, and archives the result at `tar_file`.
+#[cfg(feature = "distributed")]
+fn make_compressed_worker_project(
+    project_name: &str,
+    worker_sources: Vec<(std::path::PathBuf, String)>,
+    tar_file: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use turbolift_internals::{build_project, CACHE_PATH};
+
+    // copy all files in repo into cache
+    let function_cache_proj_path = CACHE_PATH.join(project_name);
+    fs::create_dir_all(&function_cache_proj_path)?;
+    let mut files_to_copy: Vec<PathBuf> = Vec::new();
+    let project_dir = extract_function::project_dir();
+    for entry in fs::read_dir(&project_dir)? {
+        let path = entry?.path();
+        // compiled dependencies aren't copied: derived projects share
+        // build_project::shared_target_dir instead.
+        let file_name = path.file_name();
+        if file_name != CACHE_PATH.file_name() && file_name != Some(OsStr::new("target")) {
+            files_to_copy.push(path);
+        }
+    }
     fs_extra::copy_items(
         &files_to_copy,
-        function_cache_proj_path.clone(),
+        &function_cache_proj_path,
         &fs_extra::dir::CopyOptions {
             overwrite: true,
             ..Default::default()
         },
-    )
-    .expect("error copying items to build cache");
+    )?;
 
-    // edit project main file
-    let target_main_file = function_cache_proj_path.join("src").join("main.rs");
-    fs::write(target_main_file, main_file.to_string()).expect("error editing project main.rs");
+    for (path, source) in worker_sources {
+        fs::write(function_cache_proj_path.join(path), source)?;
+    }
 
     // modify cargo.toml (edit package info & add actix + json_serde deps)
     build_project::edit_cargo_file(
-        PathBuf::from_str(".")
-            .expect("could not find project dir")
-            .canonicalize()
-            .expect("could not canonicalize path to project dir")
-            .as_path(),
+        project_dir.canonicalize()?.as_path(),
         &function_cache_proj_path.join("Cargo.toml"),
-        &original_target_function_name,
-    )
-    .expect("error editing cargo file");
+        project_name,
+    )?;
 
     // lint project
     if let Err(e) = build_project::lint(&function_cache_proj_path) {
@@ -183,84 +689,177 @@ pub fn on(distribution_platform_: TokenStream, function_: TokenStream) -> TokenS
     // // ^ todo
 
     // compress project source files
-    let project_source_binary = {
-        let tar = extract_function::make_compressed_proj_src(&function_cache_proj_path);
-        let tar_file = CACHE_PATH.join(original_target_function_name.clone() + "_source.tar");
-        fs::write(&tar_file, tar).expect("failure writing bin");
-        TokenStream2::from_str(&format!(
-            "std::include_bytes!(\"{}\")",
-            tar_file
-                .canonicalize()
-                .expect("error canonicalizing tar file location")
-                .to_str()
-                .expect("failure converting file path to str")
-        ))
-        .expect("syntax error while embedding project tar.")
-    };
-
-    // generate API function for the microservice
-    let declare_and_dispatch = q! {
-        extern crate turbolift;
-
-        // dispatch call and process response
-        #[turbolift::tracing::instrument]
-        async fn #original_target_function_ident(#typed_params) ->
-            turbolift::DistributionResult<#result_type> {
-            use std::time::Duration;
-            use turbolift::distributed_platform::DistributionPlatform;
-            use turbolift::DistributionResult;
-            use turbolift::tokio_compat_02::FutureExt;
-            use turbolift::uuid::Uuid;
-
-            let mut platform = #distribution_platform.lock().await;
-
-            if !platform.has_declared(#original_target_function_name) {
-                platform
-                    .declare(#original_target_function_name, #project_source_binary)
-                    .compat()
-                    .await?;
-            }
-
-            let params = #params_vec.join("/");
-            let resp_string = platform
-                .dispatch(
-                    #original_target_function_name,
-                    params.to_string()
-                )
-                .compat()
-                .await?;
-            Ok(turbolift::serde_json::from_str(&resp_string)?)
-        }
-    };
-    declare_and_dispatch.into()
+    let tar = extract_function::make_compressed_proj_src(&function_cache_proj_path)?;
+    fs::write(tar_file, tar)?;
+    Ok(())
 }
 
 #[cfg(not(feature = "distributed"))]
 #[proc_macro_attribute]
-pub fn on(_distribution_platform: TokenStream, function_: TokenStream) -> TokenStream {
+pub fn on(distribution_platform_: TokenStream, function_: TokenStream) -> TokenStream {
+    use proc_macro2::{Ident, Span};
+    use quote::{format_ident, ToTokens};
+
+    // the platform isn't used without distribution, but the arguments should
+    // still be valid so that enabling the feature doesn't surface new errors.
+    let mut args = match syn::parse::<OnArgs>(distribution_platform_) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
     // convert proc_macro::TokenStream to proc_macro2::TokenStream
     let function = TokenStream2::from(function_);
-    let mut wrapped_original_function = extract_function::get_fn_item(function);
+    let mut wrapped_original_function = match extract_function::get_fn_item(function) {
+        Ok(function) => function,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if let Err(e) =
+        attributes::take_with_attributes(&mut args, &mut wrapped_original_function.attrs)
+    {
+        return e.to_compile_error().into();
+    }
+    let client_attributes =
+        attributes::take_client_attributes(&mut wrapped_original_function.attrs);
+    let vis = wrapped_original_function.vis.clone();
+    let receiver = match extract_function::get_receiver(&wrapped_original_function.sig) {
+        Ok(receiver) => receiver,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if let Err(e) = extract_function::check_signature(&wrapped_original_function.sig) {
+        return e.to_compile_error().into();
+    }
+    let instantiations =
+        match extract_function::get_instantiations(&wrapped_original_function, &args.instantiate) {
+            Ok(instantiations) => instantiations,
+            Err(e) => return e.to_compile_error().into(),
+        };
     let original_target_function_ident = wrapped_original_function.sig.ident.clone();
     let signature = wrapped_original_function.sig.clone();
-    let typed_params = signature.inputs;
+    let serialization_checks = extract_function::make_serialization_checks(&signature);
+    let typed_params = extract_function::without_mutability(signature.inputs);
     let untyped_params = extract_function::to_untyped_params(typed_params.clone());
     let output_type = extract_function::get_result_type(&signature.output);
-    wrapped_original_function.sig.ident = Ident::new("wrapped_function", Span::call_site());
-
-    let async_function = q! {
-        extern crate turbolift;
+    let generics = extract_function::with_distribution_bounds(
+        &original_target_function_ident,
+        &signature.generics,
+        &typed_params,
+        &output_type,
+    );
+    let where_clause = &generics.where_clause;
+    let instantiation_trait = if instantiations.is_empty() {
+        TokenStream2::new()
+    } else {
+        extract_function::make_instantiation_trait(
+            &original_target_function_ident,
+            &vis,
+            &instantiations,
+        )
+    };
 
-        #[turbolift::tracing::instrument]
-        async fn #original_target_function_ident(#typed_params) -> turbolift::DistributionResult<#output_type> {
+    // a nested function can't use `self` or `Self`, so methods and associated
+    // functions that refer to their type keep the raw function next to the wrapper
+    let in_impl = receiver.is_some()
+        || extract_function::mentions_self_type(wrapped_original_function.to_token_stream());
+    if in_impl && !instantiations.is_empty() {
+        return syn::Error::new_spanned(
+            &signature.generics,
+            "generic methods and associated functions can't be distributed",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let async_function = if in_impl {
+        let raw_function_ident = format_ident!("{}_raw", original_target_function_ident);
+        wrapped_original_function.sig.ident = raw_function_ident.clone();
+        let redirect_call = extract_function::make_redirect_call(
+            &raw_function_ident,
+            &wrapped_original_function.sig,
+            true,
+            receiver.as_ref(),
+            &untyped_params,
+        );
+        let instrument = instrument_attribute(receiver.is_some(), false);
+        q! {
+            #[doc(hidden)]
             #wrapped_original_function
-            Ok(wrapped_function(#untyped_params))
+
+            #(#client_attributes)*
+            #instrument
+            #vis async fn #original_target_function_ident(#typed_params) -> turbolift::DistributionResult<#output_type> {
+                #serialization_checks
+                Ok(#redirect_call)
+            }
+        }
+    } else {
+        let wrapped_function_ident = Ident::new("wrapped_function", Span::call_site());
+        wrapped_original_function.sig.ident = wrapped_function_ident.clone();
+        wrapped_original_function.vis = syn::Visibility::Inherited;
+        let instrument = instrument_attribute(false, !instantiations.is_empty());
+        let redirect_call = extract_function::make_redirect_call(
+            &wrapped_function_ident,
+            &wrapped_original_function.sig,
+            false,
+            None,
+            &untyped_params,
+        );
+        q! {
+            #instantiation_trait
+
+            #(#client_attributes)*
+            #instrument
+            #vis async fn #original_target_function_ident #generics(#typed_params) -> turbolift::DistributionResult<#output_type> #where_clause {
+                #serialization_checks
+                #wrapped_original_function
+                Ok(#redirect_call)
+            }
         }
     };
     async_function.into()
 }
 
+/// `tracing::instrument` records parameters with `Debug`, which receivers and
+/// generic parameters don't necessarily implement.
+fn instrument_attribute(has_receiver: bool, is_generic: bool) -> TokenStream2 {
+    match (has_receiver, is_generic) {
+        (_, true) => q! { #[turbolift::tracing::instrument(skip_all)] },
+        (true, false) => q! { #[turbolift::tracing::instrument(skip(self))] },
+        (false, false) => q! { #[turbolift::tracing::instrument] },
+    }
+}
+
+/// attaches resource hints to a distributed function, e.g.
+/// `#[with(cpu = "500m", memory = "256Mi", replicas = 2, env(RUST_LOG = "debug"))]`.
+/// The hints are read by `on`, which passes them to the platform when the
+/// function is declared.
 #[proc_macro_attribute]
-pub fn with(_attr: TokenStream, _item: TokenStream) -> TokenStream {
-    unimplemented!()
+pub fn with(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr = TokenStream2::from(attr);
+    if attr.to_string() == attributes::APPLIED_WITH_MARKER {
+        return item;
+    }
+    let mut function = match extract_function::get_fn_item(TokenStream2::from(item)) {
+        Ok(function) => function,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    // `on` replaces the `with` attributes below it with a marker, so `with`
+    // only expands when it is written above `on`. Move it below `on` so that
+    // `on` sees it.
+    match function.attrs.iter().position(attributes::is_on_attribute) {
+        Some(on_position) => {
+            let on_attribute = function.attrs.remove(on_position);
+            q! {
+                #on_attribute
+                #[turbolift::with(#attr)]
+                #function
+            }
+            .into()
+        }
+        None => syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "`with` can only be used on functions that are distributed with `on`",
+        )
+        .to_compile_error()
+        .into(),
+    }
 }